@@ -0,0 +1,233 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Replays a recording made with `xi-win --record FILE`, without a window.
+//!
+//! Notifications from the core are parsed and applied to each view's state
+//! by the same `notification` module as in the app, and updates go through
+//! the same `LineCache` as in the edit view. The windowing side (menus, tab
+//! and status bars, drawing) isn't replayed; instead the resulting state of
+//! each view is printed as text, so line cache bugs can be reproduced on any
+//! platform.
+//!
+//! Usage: `xi-replay RECORDING [--at MS]`
+
+#[macro_use]
+extern crate serde_json;
+
+#[allow(dead_code)]
+#[path = "../../src/linecache.rs"]
+mod linecache;
+#[allow(dead_code)]
+#[path = "../../src/notification.rs"]
+mod notification;
+#[allow(dead_code)]
+#[path = "../../src/recorder.rs"]
+mod recorder;
+
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::path::Path;
+use std::process;
+
+use serde_json::Value;
+
+use linecache::{AnnotationType, Line, LineCache};
+use notification::{Notification, ViewInfo};
+use recorder::{Direction, Entry};
+
+/// What we know about a view from the recorded traffic.
+struct View {
+    line_cache: LineCache,
+    info: ViewInfo,
+    /// Last line the core asked to scroll to.
+    scroll_to: Option<usize>,
+    closed: bool,
+}
+
+impl View {
+    fn new() -> View {
+        View {
+            line_cache: LineCache::new(),
+            info: ViewInfo::default(),
+            scroll_to: None,
+            closed: false,
+        }
+    }
+}
+
+/// Dispatches recorded messages to the views, like the front end does.
+#[derive(Default)]
+struct Replay {
+    views: BTreeMap<String, View>,
+    /// Methods of requests waiting for a response, by id.
+    requests: HashMap<u64, String>,
+}
+
+impl Replay {
+    fn dispatch(&mut self, entry: &Entry) {
+        let msg = &entry.msg;
+        match entry.direction {
+            Direction::ToCore => {
+                let method = msg["method"].as_str().unwrap_or_default();
+                if let Some(id) = msg["id"].as_u64() {
+                    self.requests.insert(id, method.to_owned());
+                } else if method == "close_view" {
+                    if let Some(view_id) = msg["params"]["view_id"].as_str() {
+                        self.view(view_id).closed = true;
+                    }
+                }
+            }
+            Direction::FromCore => {
+                if let Some(method) = msg["method"].as_str() {
+                    self.notification(method, &msg["params"]);
+                } else if let Some(id) = msg["id"].as_u64() {
+                    let method = self.requests.remove(&id);
+                    if method.as_deref() == Some("new_view") {
+                        if let Some(view_id) = msg["result"].as_str() {
+                            self.views.insert(view_id.to_owned(), View::new());
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn notification(&mut self, method: &str, params: &Value) {
+        let notification = match Notification::parse(method, params) {
+            Some(notification) => notification,
+            None => return,
+        };
+        let view = match notification.view_id() {
+            Some(view_id) => self.view(view_id),
+            None => return,
+        };
+        view.info.apply(&notification);
+        match notification {
+            Notification::Update { update, .. } => {
+                view.line_cache.apply_update(update);
+            }
+            Notification::ScrollTo { line, .. } => view.scroll_to = Some(line),
+            _ => (),
+        }
+    }
+
+    /// The view with this id, created if it's new.
+    fn view(&mut self, view_id: &str) -> &mut View {
+        self.views.entry(view_id.to_owned()).or_insert_with(View::new)
+    }
+
+    fn print(&self) {
+        for (view_id, view) in &self.views {
+            let cache = &view.line_cache;
+            println!("== {} ({} lines, language {}, scroll_to {:?}{}{})", view_id,
+                cache.height(), view.info.language.as_deref().unwrap_or("none"),
+                view.scroll_to, if view.info.pristine { "" } else { ", unsaved" },
+                if view.closed { ", closed" } else { "" });
+            for ix in 0..cache.height() {
+                match cache.get_line(ix) {
+                    Some(line) => println!("{:5} {}", ix, render_line(line,
+                        &cache.annotations_for_line(ix))),
+                    None => println!("{:5} <not cached>", ix),
+                }
+            }
+        }
+    }
+}
+
+/// The line's text with carets shown as `|`, followed by its annotations.
+fn render_line(line: &Line, annotations: &[(AnnotationType, std::ops::Range<usize>)]) -> String {
+    let text = line.text().trim_end_matches(['\n', '\r']);
+    let mut result = String::new();
+    let mut offset = 0;
+    for c in text.chars() {
+        if line.cursor().contains(&offset) {
+            result.push('|');
+        }
+        result.push(c);
+        offset += c.len_utf16();
+    }
+    if line.cursor().iter().any(|&cursor| cursor >= offset) {
+        result.push('|');
+    }
+    for (ty, range) in annotations {
+        let name = match ty {
+            AnnotationType::Selection => "selection",
+            AnnotationType::Find => "find",
+            AnnotationType::Other(name) => name,
+        };
+        result.push_str(&format!("  [{} {}..{}]", name, range.start, range.end));
+    }
+    result
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (path, until) = match args.as_slice() {
+        [path] => (path, None),
+        [path, flag, ms] if flag == "--at" => match ms.parse::<u64>() {
+            Ok(ms) => (path, Some(ms)),
+            Err(_) => usage(),
+        },
+        _ => usage(),
+    };
+    let entries = match recorder::read_recording(Path::new(path)) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("could not read {}: {}", path, e);
+            process::exit(1);
+        }
+    };
+    let mut replay = Replay::default();
+    for entry in entries.iter().take_while(|e| until.is_none_or(|until| e.time <= until)) {
+        replay.dispatch(entry);
+    }
+    replay.print();
+}
+
+fn usage() -> ! {
+    eprintln!("usage: xi-replay RECORDING [--at MS]");
+    process::exit(2);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay_recording() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/edit.jsonl");
+        let mut replay = Replay::default();
+        for entry in &recorder::read_recording(&path).unwrap() {
+            replay.dispatch(entry);
+        }
+        assert!(replay.requests.is_empty());
+        assert_eq!(replay.views.keys().collect::<Vec<_>>(), vec!["view-id-1", "view-id-2"]);
+
+        let view = &replay.views["view-id-1"];
+        let cache = &view.line_cache;
+        let lines = (0..cache.height())
+            .map(|ix| render_line(cache.get_line(ix).unwrap(), &cache.annotations_for_line(ix)))
+            .collect::<Vec<_>>();
+        assert_eq!(lines, vec!["// hi", "|fn main() {  [selection 0..2]", "}"]);
+        assert_eq!(view.info.language.as_deref(), Some("Rust"));
+        assert!(!view.info.pristine);
+        assert_eq!(view.scroll_to, Some(1));
+        assert!(!view.closed);
+
+        let view = &replay.views["view-id-2"];
+        assert_eq!(view.line_cache.height(), 0);
+        assert!(view.closed);
+    }
+}
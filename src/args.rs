@@ -0,0 +1,179 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Command line parsing.
+//!
+//! Usage: `xi-win [--config-dir DIR] [--core-path PATH] [--log-level LEVEL] [--trace-rpc]
+//! [--record FILE] [FILE[:LINE[:COL]] | -]...`
+
+use std::path::PathBuf;
+
+use log::LevelFilter;
+
+use logging;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Args {
+    /// Documents to open, each in its own tab.
+    pub inputs: Vec<Input>,
+    /// Overrides the default config directory.
+    pub config_dir: Option<PathBuf>,
+    /// Runs this core executable instead of the built-in core.
+    pub core_path: Option<PathBuf>,
+    pub log_level: Option<LevelFilter>,
+    /// Logs every RPC message, in both directions.
+    pub trace_rpc: bool,
+    /// Records every RPC message to this file, for replay with `xi-replay`.
+    pub record: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Input {
+    File {
+        path: String,
+        /// 1-based line and column to put the caret at.
+        position: Option<(usize, usize)>,
+    },
+    /// `-`: a new buffer holding what's read from stdin.
+    Stdin,
+}
+
+/// Parses the arguments, not including the program name.
+pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Args, String> {
+    let mut result = Args::default();
+    let mut args = args.into_iter();
+    let mut options_done = false;
+    while let Some(arg) = args.next() {
+        if options_done || !arg.starts_with("--") {
+            if arg == "-" && !options_done {
+                result.inputs.push(Input::Stdin);
+            } else {
+                result.inputs.push(parse_file(&arg));
+            }
+            continue;
+        }
+        let (name, value) = match arg.find('=') {
+            Some(ix) => (&arg[..ix], Some(arg[ix + 1..].to_owned())),
+            None => (arg.as_str(), None),
+        };
+        match name {
+            "--" => options_done = true,
+            "--trace-rpc" => result.trace_rpc = true,
+            "--config-dir" | "--core-path" | "--log-level" | "--record" => {
+                let value = value.or_else(|| args.next())
+                    .ok_or_else(|| format!("{} needs a value", name))?;
+                match name {
+                    "--config-dir" => result.config_dir = Some(PathBuf::from(value)),
+                    "--core-path" => result.core_path = Some(PathBuf::from(value)),
+                    "--record" => result.record = Some(PathBuf::from(value)),
+                    _ => result.log_level = Some(logging::parse_level(&value)
+                        .ok_or_else(|| format!("unknown log level {}", value))?),
+                }
+            }
+            _ => return Err(format!("unknown option {}", name)),
+        }
+    }
+    Ok(result)
+}
+
+/// Parses `path`, `path:line` or `path:line:col`.
+fn parse_file(arg: &str) -> Input {
+    let (path, position) = match split_number(arg) {
+        Some((rest, last)) => match split_number(rest) {
+            Some((path, line)) => (path, Some((line, last))),
+            None => (rest, Some((last, 1))),
+        },
+        None => (arg, None),
+    };
+    let position = position.map(|(line, col)| (line.max(1), col.max(1)));
+    Input::File { path: path.to_owned(), position }
+}
+
+/// Splits a trailing `:number` off `s`, if there's something before it.
+///
+/// Drive letters (`C:\...`) are left alone since what follows isn't a number.
+fn split_number(s: &str) -> Option<(&str, usize)> {
+    let ix = s.rfind(':')?;
+    let (rest, digits) = (&s[..ix], &s[ix + 1..]);
+    if rest.is_empty() || digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok().map(|n| (rest, n))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &[&str]) -> Result<Args, String> {
+        parse(args.iter().map(|&arg| arg.to_owned()))
+    }
+
+    fn file(path: &str, position: Option<(usize, usize)>) -> Input {
+        Input::File { path: path.to_owned(), position }
+    }
+
+    #[test]
+    fn file_positions() {
+        assert_eq!(parse_file("foo.rs"), file("foo.rs", None));
+        assert_eq!(parse_file("foo.rs:42:7"), file("foo.rs", Some((42, 7))));
+        assert_eq!(parse_file("foo.rs:42"), file("foo.rs", Some((42, 1))));
+        assert_eq!(parse_file(r"C:\x\y.rs:3"), file(r"C:\x\y.rs", Some((3, 1))));
+        assert_eq!(parse_file(r"C:\x\y.rs"), file(r"C:\x\y.rs", None));
+        assert_eq!(parse_file("foo.rs:0:0"), file("foo.rs", Some((1, 1))));
+    }
+
+    #[test]
+    fn file_names_with_colons() {
+        assert_eq!(parse_file("foo.rs:abc"), file("foo.rs:abc", None));
+        assert_eq!(parse_file("foo.rs:3:abc"), file("foo.rs:3:abc", None));
+        assert_eq!(parse_file(":3"), file(":3", None));
+        assert_eq!(split_number("foo:"), None);
+    }
+
+    #[test]
+    fn stdin_and_dashes() {
+        let args = parse_args(&["-", "a.rs:2", "--", "-b.rs", "--c.rs", "-"]).unwrap();
+        assert_eq!(args.inputs, vec![
+            Input::Stdin,
+            file("a.rs", Some((2, 1))),
+            file("-b.rs", None),
+            file("--c.rs", None),
+            file("-", None),
+        ]);
+    }
+
+    #[test]
+    fn options() {
+        let expected = Some(PathBuf::from("dir"));
+        assert_eq!(parse_args(&["--config-dir=dir"]).unwrap().config_dir, expected);
+        assert_eq!(parse_args(&["--config-dir", "dir"]).unwrap().config_dir, expected);
+        let args = parse_args(&["--core-path", "xi-core.exe", "--log-level=Debug",
+            "--trace-rpc", "--record", "rec.jsonl", "a.rs"]).unwrap();
+        assert_eq!(args.core_path, Some(PathBuf::from("xi-core.exe")));
+        assert_eq!(args.log_level, Some(LevelFilter::Debug));
+        assert!(args.trace_rpc);
+        assert_eq!(args.record, Some(PathBuf::from("rec.jsonl")));
+        assert_eq!(args.inputs, vec![file("a.rs", None)]);
+        assert_eq!(parse_args(&[]).unwrap(), Args::default());
+    }
+
+    #[test]
+    fn bad_options() {
+        assert_eq!(parse_args(&["--config-dir"]), Err("--config-dir needs a value".to_owned()));
+        assert_eq!(parse_args(&["--frobnicate"]), Err("unknown option --frobnicate".to_owned()));
+        assert_eq!(parse_args(&["--log-level", "loud"]),
+            Err("unknown log level loud".to_owned()));
+    }
+}
@@ -0,0 +1,129 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Caret appearance and blinking.
+
+use std::time::Instant;
+
+use winapi::um::winbase::INFINITE;
+use winapi::um::winuser::GetCaretBlinkTime;
+
+/// Width used for block and underline carets when there is no character
+/// under the caret (end of line).
+const DEFAULT_ADVANCE: f32 = 8.0;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CaretShape {
+    /// A vertical line at the insertion point.
+    Bar,
+    /// A box covering the character after the insertion point.
+    Block,
+    /// A horizontal line under the character after the insertion point.
+    Underline,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct CaretStyle {
+    pub shape: CaretShape,
+    /// Thickness in px of the bar or underline. Ignored for block carets.
+    pub width: f32,
+}
+
+impl Default for CaretStyle {
+    fn default() -> CaretStyle {
+        CaretStyle {
+            shape: CaretShape::Bar,
+            width: 1.0,
+        }
+    }
+}
+
+impl CaretStyle {
+    /// The rectangle covered by a caret, as (left, top, right, bottom).
+    ///
+    /// `x0` is the insertion point and `x1` the trailing edge of the
+    /// following character (or `x0` at the end of a line), both in the
+    /// same coordinates as `y`, the top of the line.
+    pub fn rect(&self, x0: f32, x1: f32, y: f32, line_height: f32) -> (f32, f32, f32, f32) {
+        let x1 = if x1 > x0 { x1 } else { x0 + DEFAULT_ADVANCE };
+        match self.shape {
+            CaretShape::Bar => (x0, y, x0 + self.width, y + line_height),
+            CaretShape::Block => (x0, y, x1, y + line_height),
+            CaretShape::Underline => (x0, y + line_height - self.width, x1, y + line_height),
+        }
+    }
+}
+
+/// Caret blink state for one view.
+pub struct Blink {
+    enabled: bool,
+    /// Carets are shown for the first blink period after this time.
+    epoch: Instant,
+    visible: bool,
+}
+
+impl Blink {
+    pub fn new() -> Blink {
+        Blink {
+            enabled: true,
+            epoch: Instant::now(),
+            visible: true,
+        }
+    }
+
+    /// Shows the carets and restarts the blink cycle.
+    pub fn reset(&mut self) {
+        self.epoch = Instant::now();
+        self.visible = true;
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        self.reset();
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn is_visible(&self) -> bool {
+        self.visible
+    }
+
+    /// Updates visibility for the current time. Returns `true` if it changed.
+    pub fn tick(&mut self) -> bool {
+        let visible = match system_blink_time() {
+            Some(period) if self.enabled => {
+                let elapsed = self.epoch.elapsed();
+                let ms = elapsed.as_secs() * 1000 + elapsed.subsec_millis() as u64;
+                (ms / period) % 2 == 0
+            }
+            _ => true,
+        };
+        let changed = visible != self.visible;
+        self.visible = visible;
+        changed
+    }
+}
+
+/// The system caret blink time in ms (the duration of each on or off phase),
+/// or `None` if the user has turned blinking off.
+pub fn system_blink_time() -> Option<u64> {
+    let ms = unsafe { GetCaretBlinkTime() };
+    if ms == 0 || ms == INFINITE {
+        None
+    } else {
+        Some(ms as u64)
+    }
+}
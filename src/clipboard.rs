@@ -0,0 +1,45 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Access to the system clipboard.
+
+use std::ptr::null_mut;
+
+use winapi::um::winbase::{GlobalLock, GlobalUnlock};
+use winapi::um::winuser::{CloseClipboard, GetClipboardData, OpenClipboard, CF_UNICODETEXT};
+
+/// Returns the text on the clipboard, if there is any.
+pub fn get_text() -> Option<String> {
+    unsafe {
+        if OpenClipboard(null_mut()) == 0 {
+            return None;
+        }
+        let mut result = None;
+        let handle = GetClipboardData(CF_UNICODETEXT);
+        if !handle.is_null() {
+            let ptr = GlobalLock(handle) as *const u16;
+            if !ptr.is_null() {
+                let mut len = 0;
+                while *ptr.offset(len) != 0 {
+                    len += 1;
+                }
+                let slice = ::std::slice::from_raw_parts(ptr, len as usize);
+                result = String::from_utf16(slice).ok();
+                GlobalUnlock(handle);
+            }
+        }
+        CloseClipboard();
+        result
+    }
+}
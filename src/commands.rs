@@ -0,0 +1,365 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The registry of named commands.
+//!
+//! Menus and key bindings all look commands up here, so each command has
+//! one name, one title and one set of default keys wherever it's run from.
+
+use druid::ListenerCtx;
+
+use caret::CaretShape;
+use keys::KeyChord;
+use menus::MenuEntries;
+use settings::Domain;
+use App;
+
+/// What running a command does.
+#[derive(Clone, Copy)]
+pub enum Action {
+    /// Calls into the app.
+    App(fn(&App, &mut ListenerCtx)),
+    /// Changes the focused view's config like the View > Settings menu item
+    /// of the command's entry.
+    Setting,
+    /// Sends the edit action named like the command to the core, for the
+    /// focused view.
+    Edit,
+}
+
+pub struct Command {
+    /// The name commands are looked up by. For `Action::Edit`, this is also
+    /// the method of the edit action.
+    pub name: &'static str,
+    /// The title shown in menus, with `&` before the mnemonic.
+    pub title: &'static str,
+    /// The title shown in the command palette, if the title needs the
+    /// context of its menu.
+    palette_title: Option<&'static str>,
+    /// The menu item running this command, if any.
+    pub entry: Option<MenuEntries>,
+    /// Default key bindings. The user's keymap can override them.
+    pub keys: Vec<KeyChord>,
+    pub action: Action,
+}
+
+impl Command {
+    fn new(name: &'static str, title: &'static str, action: Action) -> Command {
+        Command { name, title, palette_title: None, entry: None, keys: Vec::new(), action }
+    }
+
+    fn app(name: &'static str, title: &'static str, f: fn(&App, &mut ListenerCtx)) -> Command {
+        Command::new(name, title, Action::App(f))
+    }
+
+    fn edit(name: &'static str, title: &'static str) -> Command {
+        Command::new(name, title, Action::Edit)
+    }
+
+    fn setting(name: &'static str, title: &'static str, entry: MenuEntries) -> Command {
+        Command::new(name, title, Action::Setting).menu(entry)
+    }
+
+    fn menu(mut self, entry: MenuEntries) -> Command {
+        self.entry = Some(entry);
+        self
+    }
+
+    fn palette(mut self, title: &'static str) -> Command {
+        self.palette_title = Some(title);
+        self
+    }
+
+    /// Adds default key bindings, like "Ctrl+Shift+S".
+    fn keys(mut self, keys: &[&str]) -> Command {
+        for key in keys {
+            let chord = KeyChord::parse(key)
+                .unwrap_or_else(|e| panic!("bad key for command {}: {}", self.name, e));
+            self.keys.push(chord);
+        }
+        self
+    }
+
+    /// The title shown in the command palette, without mnemonics.
+    pub fn palette_title(&self) -> String {
+        self.palette_title.unwrap_or(self.title).replace('&', "")
+    }
+
+    /// The id of the command's menu item.
+    pub fn id(&self) -> Option<u32> {
+        self.entry.map(|entry| entry as u32)
+    }
+
+    pub fn run(&self, app: &App, ctx: &mut ListenerCtx) {
+        match self.action {
+            Action::App(f) => f(app, ctx),
+            Action::Setting => {
+                if let Some(id) = self.id() {
+                    app.modify_config(id);
+                }
+            }
+            Action::Edit => app.send_focused_edit(self.name),
+        }
+    }
+}
+
+pub struct Registry {
+    commands: Vec<Command>,
+}
+
+impl Registry {
+    /// The registry of built-in commands.
+    pub fn new() -> Registry {
+        Registry { commands: builtin_commands() }
+    }
+
+    pub fn commands(&self) -> &[Command] {
+        &self.commands
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Command> {
+        self.commands.iter().find(|command| command.name == name)
+    }
+
+    /// The command with the given menu item id.
+    pub fn by_id(&self, id: u32) -> Option<&Command> {
+        self.commands.iter().find(|command| command.id() == Some(id))
+    }
+}
+
+fn builtin_commands() -> Vec<Command> {
+    use self::MenuEntries::*;
+    let mut commands = vec![
+        // File
+        Command::app("open", "&Open…", |app, ctx| app.open_file(ctx))
+            .menu(Open).keys(&["Ctrl+O"]),
+        Command::app("save", "&Save", |app, ctx| {
+            let view_id = app.get_state().focused.clone();
+            if let Some(view_id) = view_id {
+                app.save_view(&view_id, ctx);
+            }
+        }).menu(Save).keys(&["Ctrl+S"]),
+        Command::app("save_as", "Save &as…", |app, ctx| app.save_as(ctx))
+            .menu(SaveAs).keys(&["Ctrl+Shift+S"]),
+        Command::app("close_tab", "&Close Tab", |app, ctx| app.close_focused_tab(ctx))
+            .menu(CloseTab).keys(&["Ctrl+W"]),
+        Command::app("preferences", "&Preferences",
+            |app, _| app.open_preferences(app.focused_handle())).menu(Preferences),
+        Command::app("clear_recent", "&Clear Recent", |app, _| app.clear_recent())
+            .menu(ClearRecent),
+        Command::app("exit", "E&xit", |app, ctx| {
+            if app.confirm_close_all(ctx) {
+                // The rest happens in `App::shutdown` once the run loop ends.
+                ctx.close();
+            }
+        }).menu(Exit),
+
+        // Edit
+        Command::edit("undo", "&Undo").menu(Undo).keys(&["Ctrl+Z"]),
+        Command::edit("redo", "&Redo").menu(Redo).keys(&["Ctrl+Y", "Ctrl+Shift+Z"]),
+        // TODO: cut, copy, paste (requires pasteboard)
+        Command::app("cut", "Cu&t", |_, _| warn!("cut is not supported yet"))
+            .menu(Cut).keys(&["Ctrl+X"]),
+        Command::app("copy", "&Copy", |_, _| warn!("copy is not supported yet"))
+            .menu(Copy).keys(&["Ctrl+C"]),
+        Command::app("paste", "&Paste", |_, _| warn!("paste is not supported yet"))
+            .menu(Paste).keys(&["Ctrl+V"]),
+        Command::edit("uppercase", "Upper Case").menu(UpperCase),
+        Command::edit("lowercase", "Lower Case").menu(LowerCase),
+        Command::edit("transpose", "Transpose").menu(Transpose).keys(&["Ctrl+T"]),
+        Command::app("go_to_line", "&Go to Line…", |app, _| app.show_go_to_line())
+            .menu(GoToLine).keys(&["Ctrl+G"]),
+        Command::edit("indent", "Indent").keys(&["Ctrl+]"]),
+        Command::edit("outdent", "Outdent").keys(&["Ctrl+["]),
+        Command::edit("insert_newline", "Insert Newline").keys(&["Enter", "Shift+Enter"]),
+        Command::edit("insert_tab", "Insert Tab").keys(&["Tab", "Shift+Tab"]),
+        Command::edit("delete_backward", "Delete Backward")
+            .keys(&["Backspace", "Shift+Backspace"]),
+        Command::edit("delete_forward", "Delete Forward").keys(&["Delete", "Shift+Delete"]),
+        Command::edit("delete_word_backward", "Delete Word Backward").keys(&["Ctrl+Backspace"]),
+        Command::edit("delete_word_forward", "Delete Word Forward").keys(&["Ctrl+Delete"]),
+        // Should be "delete to beginning of paragraph", but that's not supported.
+        Command::edit("delete_to_beginning_of_line", "Delete to Beginning of Line")
+            .keys(&["Ctrl+Shift+Backspace"]),
+        Command::edit("delete_to_end_of_paragraph", "Delete to End of Paragraph")
+            .keys(&["Ctrl+Shift+Delete"]),
+        // Edit actions of the core that are only in the command palette.
+        Command::edit("duplicate_line", "Duplicate Line"),
+        Command::edit("sort_lines", "Sort Lines"),
+        Command::edit("toggle_comment", "Toggle Comment"),
+        Command::edit("reindent", "Reindent"),
+        Command::edit("capitalize", "Capitalize"),
+        Command::edit("increase_number", "Increase Number"),
+        Command::edit("decrease_number", "Decrease Number"),
+
+        // Selection
+        Command::edit("add_selection_above", "Add Cursor Above")
+            .menu(AddCursorAbove).keys(&["Ctrl+Alt+Up"]),
+        Command::edit("add_selection_below", "Add Cursor Below")
+            .menu(AddCursorBelow).keys(&["Ctrl+Alt+Down"]),
+        Command::edit("cancel_operation", "Single Selection")
+            .menu(SingleSelection).keys(&["Escape"]),
+        Command::edit("select_all", "Select All").menu(SelectAll).keys(&["Ctrl+A"]),
+        Command::edit("collapse_selections", "Collapse Selections"),
+        Command::edit("selection_into_lines", "Split Selection into Lines"),
+
+        // Movement
+        Command::edit("move_up", "Move Up").keys(&["Up"]),
+        Command::edit("move_down", "Move Down").keys(&["Down"]),
+        Command::edit("move_left", "Move Left").keys(&["Left"]),
+        Command::edit("move_right", "Move Right").keys(&["Right"]),
+        Command::edit("move_word_left", "Move Word Left")
+            .keys(&["Ctrl+Left", "Alt+Left", "Ctrl+Alt+Left"]),
+        Command::edit("move_word_right", "Move Word Right")
+            .keys(&["Ctrl+Right", "Alt+Right", "Ctrl+Alt+Right"]),
+        Command::edit("move_to_left_end_of_line", "Move to Beginning of Line").keys(&["Home"]),
+        Command::edit("move_to_right_end_of_line", "Move to End of Line").keys(&["End"]),
+        Command::edit("move_to_beginning_of_document", "Move to Beginning of Document")
+            .keys(&["Ctrl+Home"]),
+        Command::edit("move_to_end_of_document", "Move to End of Document")
+            .keys(&["Ctrl+End"]),
+        Command::edit("scroll_page_up", "Page Up").keys(&["PageUp"]),
+        Command::edit("scroll_page_down", "Page Down").keys(&["PageDown"]),
+        Command::edit("move_up_and_modify_selection", "Select Up").keys(&["Shift+Up"]),
+        Command::edit("move_down_and_modify_selection", "Select Down").keys(&["Shift+Down"]),
+        Command::edit("move_left_and_modify_selection", "Select Left").keys(&["Shift+Left"]),
+        Command::edit("move_right_and_modify_selection", "Select Right").keys(&["Shift+Right"]),
+        Command::edit("move_word_left_and_modify_selection", "Select Word Left")
+            .keys(&["Ctrl+Shift+Left", "Alt+Shift+Left", "Ctrl+Alt+Shift+Left"]),
+        Command::edit("move_word_right_and_modify_selection", "Select Word Right")
+            .keys(&["Ctrl+Shift+Right", "Alt+Shift+Right", "Ctrl+Alt+Shift+Right"]),
+        Command::edit("move_to_left_end_of_line_and_modify_selection",
+            "Select to Beginning of Line").keys(&["Shift+Home"]),
+        Command::edit("move_to_right_end_of_line_and_modify_selection",
+            "Select to End of Line").keys(&["Shift+End"]),
+        Command::edit("move_to_beginning_of_document_and_modify_selection",
+            "Select to Beginning of Document").keys(&["Ctrl+Shift+Home"]),
+        Command::edit("move_to_end_of_document_and_modify_selection",
+            "Select to End of Document").keys(&["Ctrl+Shift+End"]),
+        Command::edit("page_up_and_modify_selection", "Select Page Up").keys(&["Shift+PageUp"]),
+        Command::edit("page_down_and_modify_selection", "Select Page Down")
+            .keys(&["Shift+PageDown"]),
+
+        // View
+        Command::app("caret_bar", "&Bar", |app, _| app.set_caret_shape(CaretShape::Bar))
+            .menu(CaretBar).palette("Caret: Bar"),
+        Command::app("caret_block", "B&lock", |app, _| app.set_caret_shape(CaretShape::Block))
+            .menu(CaretBlock).palette("Caret: Block"),
+        Command::app("caret_underline", "&Underline",
+            |app, _| app.set_caret_shape(CaretShape::Underline))
+            .menu(CaretUnderline).palette("Caret: Underline"),
+        Command::app("toggle_caret_blink", "Bli&nking", |app, _| app.toggle_caret_blink())
+            .menu(CaretBlink).palette("Caret: Toggle Blinking"),
+        Command::app("caret_width_1", "Width &1", |app, _| app.set_caret_width(1))
+            .menu(CaretWidth1).palette("Caret: Width 1"),
+        Command::app("caret_width_2", "Width &2", |app, _| app.set_caret_width(2))
+            .menu(CaretWidth2).palette("Caret: Width 2"),
+        Command::app("caret_width_3", "Width &3", |app, _| app.set_caret_width(3))
+            .menu(CaretWidth3).palette("Caret: Width 3"),
+        Command::app("next_tab", "&Next Tab", |app, _| app.cycle_tab(1))
+            .menu(NextTab).keys(&["Ctrl+Tab"]),
+        Command::app("previous_tab", "&Previous Tab", |app, _| app.cycle_tab(-1))
+            .menu(PreviousTab).keys(&["Ctrl+Shift+Tab"]),
+        Command::app("command_palette", "Co&mmand Palette…", |app, _| app.show_palette())
+            .menu(CommandPalette).keys(&["Ctrl+Shift+P"]),
+
+        // View > Settings
+        Command::app("settings_domain_general", "Apply Settings to All Files",
+            |app, _| app.set_settings_domain(Domain::General)).menu(DomainGeneral),
+        Command::app("settings_domain_language", "Apply Settings to Current Language",
+            |app, _| app.set_settings_domain(Domain::Language)).menu(DomainLanguage),
+        Command::app("settings_domain_view", "Apply Settings to This View",
+            |app, _| app.set_settings_domain(Domain::View)).menu(DomainView),
+        Command::setting("tab_size_2", "Tab Size 2", TabSize2),
+        Command::setting("tab_size_4", "Tab Size 4", TabSize4),
+        Command::setting("tab_size_8", "Tab Size 8", TabSize8),
+        Command::setting("toggle_translate_tabs", "Toggle Translate Tabs to Spaces",
+            TranslateTabs),
+        Command::setting("toggle_word_wrap", "Toggle Word Wrap", WordWrap),
+        Command::setting("toggle_auto_indent", "Toggle Auto-Indent", AutoIndent),
+        Command::app("settings_dialog", "&Edit Settings…", |app, _| app.show_settings_dialog())
+            .menu(SettingsDialog).palette("Settings: Edit Settings…"),
+    ];
+    // Debugging aids, only in the command palette of debug builds.
+    if cfg!(debug_assertions) {
+        commands.push(Command::app("send_plugin_rpc", "Debug: Send Plugin RPC from Clipboard",
+            |app, _| app.send_plugin_rpc_from_clipboard()));
+    }
+    commands
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::{HashMap, HashSet};
+
+    use menus;
+    use settings;
+
+    /// Looks for inconsistencies between the commands and the menus,
+    /// returning a description of each.
+    fn problems(registry: &Registry) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut names = HashSet::new();
+        let mut ids = HashSet::new();
+        let mut keys = HashMap::new();
+        for command in &registry.commands {
+            if !names.insert(command.name) {
+                problems.push(format!("command {} is registered twice", command.name));
+            }
+            if let Some(id) = command.id() {
+                if !ids.insert(id) {
+                    problems.push(format!("command {} has the menu entry of another",
+                        command.name));
+                }
+            }
+            if let Action::Setting = command.action {
+                if settings::changes_for_cmd(command.id().unwrap(), &json!({})).is_none() {
+                    problems.push(format!("command {} is not a setting", command.name));
+                }
+            }
+            for key in &command.keys {
+                if let Some(other) = keys.insert(*key, command.name) {
+                    problems.push(format!("{} is bound to both {} and {}", key, other,
+                        command.name));
+                }
+            }
+        }
+        let mut entries = menus::entries(&menus::menu_model());
+        let settings_items = settings::menu_items(&json!({}), Domain::General, Some(""));
+        entries.extend(settings_items.iter().filter(|item| !item.text.is_empty())
+            .map(|item| item.id));
+        for id in entries {
+            if registry.by_id(id).is_none() {
+                problems.push(format!("menu item {:#x} has no command", id));
+            }
+        }
+        problems
+    }
+
+    #[test]
+    fn registry_is_consistent() {
+        let problems = problems(&Registry::new());
+        assert!(problems.is_empty(), "{:?}", problems);
+    }
+
+    #[test]
+    fn menu_items_have_commands() {
+        let registry = Registry::new();
+        for id in menus::entries(&menus::menu_model()) {
+            assert!(registry.by_id(id).is_some(), "menu item {:#x} has no command", id);
+        }
+    }
+}
@@ -0,0 +1,179 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tracking of what needs repainting in the edit view.
+
+use std::ops::Range;
+
+/// What needs repainting on the next paint.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Damage {
+    None,
+    /// Only the caret rectangles changed (blinking or focus).
+    Carets,
+    /// These lines changed, in document line numbers. Sorted and disjoint.
+    Lines(Vec<Range<usize>>),
+    /// The view scrolled by this many px (positive is towards the end of
+    /// the document) and nothing else changed.
+    Scroll(f32),
+    Full,
+}
+
+impl Damage {
+    /// Adds changed lines.
+    ///
+    /// Pending caret damage is dropped, so the caller must include the lines
+    /// containing carets.
+    pub fn add_lines(&mut self, lines: &[Range<usize>]) {
+        let merged = match *self {
+            Damage::None | Damage::Carets => lines.to_vec(),
+            Damage::Lines(ref old) => old.iter().chain(lines.iter()).cloned().collect(),
+            Damage::Scroll(_) | Damage::Full => {
+                *self = Damage::Full;
+                return;
+            }
+        };
+        *self = Damage::Lines(normalize(merged));
+    }
+
+    /// Adds a scroll by `dy` px. Anything other than a pure scroll of less
+    /// than `max_dy` becomes a full repaint.
+    pub fn add_scroll(&mut self, dy: f32, max_dy: f32) {
+        let total = match *self {
+            Damage::None => dy,
+            Damage::Scroll(old) => old + dy,
+            _ => {
+                *self = Damage::Full;
+                return;
+            }
+        };
+        *self = if total == 0.0 {
+            Damage::None
+        } else if total.abs() < max_dy {
+            Damage::Scroll(total)
+        } else {
+            Damage::Full
+        };
+    }
+
+    /// Adds a repaint of the carets, which are on `caret_lines`.
+    ///
+    /// Pending line damage is extended to those lines, since painting it
+    /// only repaints the carets on the lines it covers.
+    pub fn add_carets(&mut self, caret_lines: &[usize]) {
+        match *self {
+            Damage::None => *self = Damage::Carets,
+            Damage::Carets | Damage::Full => (),
+            Damage::Lines(_) => {
+                let lines = caret_lines.iter().map(|&line| line..line + 1).collect::<Vec<_>>();
+                self.add_lines(&lines);
+            }
+            Damage::Scroll(_) => *self = Damage::Full,
+        }
+    }
+}
+
+/// Sorts ranges and merges the ones that overlap or touch.
+fn normalize(mut ranges: Vec<Range<usize>>) -> Vec<Range<usize>> {
+    ranges.retain(|r| r.start < r.end);
+    ranges.sort_by_key(|r| r.start);
+    let mut result: Vec<Range<usize>> = Vec::new();
+    for r in ranges {
+        if let Some(last) = result.last_mut() {
+            if r.start <= last.end {
+                if r.end > last.end {
+                    last.end = r.end;
+                }
+                continue;
+            }
+        }
+        result.push(r);
+    }
+    result
+}
+
+#[cfg(test)]
+#[allow(clippy::single_range_in_vec_init)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_lines() {
+        let mut damage = Damage::None;
+        damage.add_lines(&[3..5, 1..2]);
+        assert_eq!(damage, Damage::Lines(vec![1..2, 3..5]));
+        damage.add_lines(&[2..3, 8..9]);
+        assert_eq!(damage, Damage::Lines(vec![1..5, 8..9]));
+
+        let mut damage = Damage::Carets;
+        damage.add_lines(&[4..6]);
+        assert_eq!(damage, Damage::Lines(vec![4..6]));
+
+        let mut damage = Damage::Scroll(10.0);
+        damage.add_lines(&[0..1]);
+        assert_eq!(damage, Damage::Full);
+        damage.add_lines(&[0..1]);
+        assert_eq!(damage, Damage::Full);
+    }
+
+    #[test]
+    fn add_scroll() {
+        let mut damage = Damage::None;
+        damage.add_scroll(10.0, 100.0);
+        assert_eq!(damage, Damage::Scroll(10.0));
+        damage.add_scroll(-30.0, 100.0);
+        assert_eq!(damage, Damage::Scroll(-20.0));
+        damage.add_scroll(20.0, 100.0);
+        assert_eq!(damage, Damage::None);
+
+        damage.add_scroll(60.0, 100.0);
+        damage.add_scroll(40.0, 100.0);
+        assert_eq!(damage, Damage::Full);
+
+        let mut damage = Damage::Lines(vec![0..1]);
+        damage.add_scroll(10.0, 100.0);
+        assert_eq!(damage, Damage::Full);
+
+        let mut damage = Damage::Carets;
+        damage.add_scroll(10.0, 100.0);
+        assert_eq!(damage, Damage::Full);
+    }
+
+    #[test]
+    fn add_carets() {
+        let mut damage = Damage::None;
+        damage.add_carets(&[3]);
+        assert_eq!(damage, Damage::Carets);
+
+        let mut damage = Damage::Lines(vec![1..3]);
+        damage.add_carets(&[2]);
+        assert_eq!(damage, Damage::Lines(vec![1..3]));
+        damage.add_carets(&[3, 7]);
+        assert_eq!(damage, Damage::Lines(vec![1..4, 7..8]));
+
+        let mut damage = Damage::Scroll(10.0);
+        damage.add_carets(&[0]);
+        assert_eq!(damage, Damage::Full);
+        damage.add_carets(&[0]);
+        assert_eq!(damage, Damage::Full);
+    }
+
+    #[test]
+    fn normalize_ranges() {
+        assert_eq!(normalize(vec![5..7, 1..3, 2..4, 4..5]), vec![1..7]);
+        assert_eq!(normalize(vec![6..8, 0..2, 3..3]), vec![0..2, 6..8]);
+        assert_eq!(normalize(vec![0..10, 2..3]), vec![0..10]);
+        assert_eq!(normalize(vec![]), vec![]);
+    }
+}
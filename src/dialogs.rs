@@ -0,0 +1,47 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Message boxes. druid doesn't have any, so these use winapi directly.
+
+use std::ffi::OsStr;
+use std::os::windows::ffi::OsStrExt;
+
+use winapi::shared::windef::HWND;
+use winapi::um::winuser::{MessageBoxW, IDNO, IDYES, MB_ICONWARNING, MB_YESNOCANCEL};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SaveChoice {
+    Save,
+    DontSave,
+    Cancel,
+}
+
+/// Asks whether to save the unsaved changes to `name` before closing it.
+/// Must be called on the UI thread.
+pub fn ask_save(hwnd: HWND, name: &str) -> SaveChoice {
+    let text = to_wide(&format!("Do you want to save the changes to {}?", name));
+    let caption = to_wide("xi-editor");
+    let result = unsafe {
+        MessageBoxW(hwnd, text.as_ptr(), caption.as_ptr(), MB_YESNOCANCEL | MB_ICONWARNING)
+    };
+    match result {
+        IDYES => SaveChoice::Save,
+        IDNO => SaveChoice::DontSave,
+        _ => SaveChoice::Cancel,
+    }
+}
+
+fn to_wide(s: &str) -> Vec<u16> {
+    OsStr::new(s).encode_wide().chain(Some(0)).collect()
+}
@@ -15,22 +15,31 @@
 //! The main edit view.
 
 use std::cmp::min;
+use std::collections::HashMap;
 use std::ops::Range;
 use std::any::Any;
-use std::sync::{Mutex, Weak};
+use std::ptr::{null, null_mut};
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::mpsc::Sender;
 use std::mem;
 
 use serde_json::Value;
 
+use winapi::shared::winerror::SUCCEEDED;
+use winapi::um::d2d1::{ID2D1RenderTarget, D2D1_BITMAP_INTERPOLATION_MODE_NEAREST_NEIGHBOR,
+    D2D1_BITMAP_PROPERTIES, D2D1_MATRIX_3X2_F, D2D1_POINT_2U, D2D1_RECT_F, D2D1_RECT_U,
+    D2D1_SIZE_U};
 use winapi::um::winuser::*;
 
 use direct2d::brush::SolidColorBrush;
+use direct2d::enums::AntialiasMode;
 use direct2d::math::*;
 use direct2d::RenderTarget;
 use directwrite;
 use directwrite::TextFormat;
+use directwrite::font_collection::FontCollection;
 
-use druid_win_shell::window::{M_ALT, M_CTRL, M_SHIFT, MouseButton};
+use druid_win_shell::window::{M_CTRL, MouseButton};
 
 use druid::Ui;
 use druid::widget::Widget;
@@ -40,33 +49,93 @@ use druid::{HandlerCtx, Id, LayoutCtx, MouseEvent, PaintCtx, KeyEvent};
 use druid::{KeyVariant};
 
 use rpc::Core;
-use linecache::LineCache;
-use textline::TextLine;
+use caret::{Blink, CaretShape, CaretStyle};
+use damage::Damage;
+use keymap::{KeyContext, Keymap, Lookup, Target};
+use keys::{self, KeyChord};
+use linecache::{AnnotationType, LineCache};
+use palette::{Palette, PaletteItem, PaletteKey};
+use settings::Domain;
+use settings_dialog::{DialogKey, SettingsDialog};
+use status_bar::ViewStatus;
+use textline::{self, TextLine};
+use theme::{solid_brush, Theme};
 
 /// The commands the EditView widget accepts through `poke`.
 pub enum EditViewCommands {
+    /// Shows the document of this view, keeping the current one for later.
     ViewId(String),
-    ApplyUpdate(Value),
-    ScrollTo(usize),
+    /// Forgets the document of this view.
+    Close(String),
+    /// The core went away: sends the text of each unsaved document that is
+    /// fully cached, by view id, then forgets all documents.
+    Recover(Sender<HashMap<String, String>>),
+    ApplyUpdate(String, Value),
+    ScrollTo(String, usize),
     Core(Weak<Mutex<Core>>),
-    Undo,
-    Redo,
-    UpperCase,
-    LowerCase,
-    Transpose,
-    AddCursorAbove,
-    AddCursorBelow,
-    SingleSelection,
-    SelectAll,
+    Theme(Theme),
+    /// Font family and size, from the view's config.
+    Font(String, f32),
+    CaretShape(CaretShape),
+    CaretWidth(f32),
+    ToggleCaretBlink,
+    /// Periodic timer tick, drives caret blinking.
+    Tick,
+    /// Whether the window has keyboard focus.
+    Focus(bool),
+    Keymap(Arc<Keymap>),
+    /// Shows the command palette with these commands.
+    ShowPalette(Vec<PaletteItem>),
+    /// Asks for a line to go to.
+    GoToLine,
+    /// Puts the caret of a view at a 0-based line and column (in chars),
+    /// once the line is loaded.
+    GoTo(String, usize, usize),
+    /// Shows the settings dialog with a title, the view's config, the
+    /// domain to store changes in and the view's language.
+    ShowSettings(String, Value, Domain, Option<String>),
+}
+
+/// Sent to listeners for keys bound to a command that the app runs.
+pub struct RunCommand(pub String);
+
+/// Sent to listeners for a command chosen in the command palette.
+pub struct PaletteChoice(pub String);
+
+/// Sent to listeners for settings changed in the settings dialog, with the
+/// domain to store them in.
+pub struct ApplySettings(pub Domain, pub Value);
+
+/// A document that isn't shown, kept so it can be switched back to.
+struct Document {
+    line_cache: LineCache,
+    scroll_offset: f32,
+    pristine: bool,
+}
+
+impl Document {
+    fn new() -> Document {
+        Document {
+            line_cache: LineCache::new(),
+            scroll_offset: 0.0,
+            pristine: true,
+        }
+    }
 }
 
 type Method = String;
 type Params = Value;
 
+/// The font face and size the edit view draws with, shared so that text can
+/// be measured for the core on the RPC thread.
+pub type SharedFont = Arc<Mutex<(String, f32)>>;
+
 /// State and behavior for one editor view.
 pub struct EditView {
     view_id: Option<String>,
     line_cache: LineCache,
+    /// Documents of the other views, by view id.
+    documents: HashMap<String, Document>,
     dwrite_factory: directwrite::Factory,
     resources: Option<Resources>,
     scroll_offset: f32,
@@ -74,47 +143,120 @@ pub struct EditView {
     viewport: Range<usize>,
     core: Weak<Mutex<Core>>,
     pending: Vec<(Method, Params)>,
+    theme: Theme,
+    font_face: String,
+    font_size: f32,
+    /// Distance between the tops of lines in px, from the font's metrics.
+    line_space: f32,
+    shared_font: SharedFont,
+    caret_style: CaretStyle,
+    blink: Blink,
+    has_focus: bool,
+    /// Caret rectangles from the last paint, with their line numbers.
+    caret_rects: Vec<(usize, (f32, f32, f32, f32))>,
+    damage: Damage,
+    pristine: bool,
+    /// The status last reported to listeners.
+    status: ViewStatus,
+    keymap: Arc<Keymap>,
+    /// The keys of a sequence that's been started, like Ctrl+K for Ctrl+K Ctrl+C.
+    pending_keys: Vec<KeyChord>,
+    /// Set when a bound key that types a character was handled, so that
+    /// the character isn't inserted too.
+    suppress_char: bool,
+    /// The command palette, when it's shown. It takes all keys.
+    palette: Option<Palette>,
+    /// The settings dialog, when it's shown. It takes all keys.
+    settings: Option<SettingsDialog>,
+    /// Caret positions to go to once their lines are loaded, by view id.
+    pending_go_to: HashMap<String, (usize, usize)>,
 }
 
 struct Resources {
     fg: SolidColorBrush,
     bg: SolidColorBrush,
     sel: SolidColorBrush,
+    find: SolidColorBrush,
+    annotation: SolidColorBrush,
+    caret: SolidColorBrush,
+    secondary_caret: SolidColorBrush,
+    line_highlight: SolidColorBrush,
     text_format: TextFormat,
 }
 
 const TOP_PAD: f32 = 6.0;
 const LEFT_PAD: f32 = 6.0;
-const LINE_SPACE: f32 = 17.0;
+/// Line spacing used until the font has been measured.
+const DEFAULT_LINE_SPACE: f32 = 17.0;
+const DEFAULT_FONT_FACE: &str = "Consolas";
+const DEFAULT_FONT_SIZE: f32 = 15.0;
 
 impl Widget for EditView {
     fn paint(&mut self, paint_ctx: &mut PaintCtx, geom: &Geometry) {
         // todo: Cache resources, and flush cache when the render target is re-created.
+        if geom.size != self.size || self.palette.is_some() || self.settings.is_some() {
+            // Overlays are painted over the text, so it's all repainted.
+            self.damage = Damage::Full;
+        }
         self.size = geom.size;
         let resources = self.create_resources(paint_ctx);
         let rt = paint_ctx.render_target();
-        let rect = RectF::from((0.0, 0.0, self.size.0, self.size.1));
-        rt.fill_rectangle(rect, &resources.bg);
-
-        let first_line = self.y_to_line(0.0);
-        let last_line = min(self.y_to_line(self.size.1) + 1, self.line_cache.height());
+        unsafe { set_translation(rt.get_raw(), geom.pos) };
+        let primary_line = self.line_cache.primary_cursor_line();
+        let (width, height) = self.size;
 
-        let x0 = LEFT_PAD;
-        let mut y = self.line_to_content_y(first_line) - self.scroll_offset;
-        for line_num in first_line..last_line {
-            if let Some(textline) = self.get_text_line(line_num) {
-                textline.draw_bg(rt, x0, y, &resources.sel);
+        match mem::replace(&mut self.damage, Damage::None) {
+            Damage::Carets => {
+                // Repaint each caret's line, clipped to the caret.
+                for &(line_num, rect) in &self.caret_rects {
+                    if let Some(textline) = self.get_text_line(line_num) {
+                        let y = self.line_to_content_y(line_num) - self.scroll_offset;
+                        rt.push_axis_aligned_clip(RectF::from(rect), AntialiasMode::Aliased);
+                        rt.fill_rectangle(rect, &resources.bg);
+                        self.paint_line_bg(rt, &resources, &textline, y);
+                        self.paint_line_fg(rt, &resources, &textline, line_num, y, primary_line);
+                        rt.pop_axis_aligned_clip();
+                    }
+                }
             }
-            y += LINE_SPACE;
-        }
-        let mut y = self.line_to_content_y(first_line) - self.scroll_offset;
-        for line_num in first_line..last_line {
-            if let Some(textline) = self.get_text_line(line_num) {
-                textline.draw_text(rt, x0, y, &resources.fg);
-                textline.draw_cursor(rt, x0, y, &resources.fg);
+            Damage::Lines(ranges) => {
+                for range in ranges {
+                    let top = self.line_to_content_y(range.start) - self.scroll_offset;
+                    let bottom = self.line_to_content_y(range.end) - self.scroll_offset;
+                    if bottom > 0.0 && top < height {
+                        self.paint_band(rt, &resources, top.max(0.0), bottom.min(height),
+                            primary_line);
+                    }
+                }
+            }
+            Damage::Scroll(dy) => {
+                // Move what's still visible, then paint the newly exposed band.
+                let (top, bottom) = (dy.max(0.0), height + dy.min(0.0));
+                let blitted = unsafe { blit_vertical(rt.get_raw(), geom.pos, width, top, bottom, dy) };
+                if blitted {
+                    for &mut (_, ref mut rect) in &mut self.caret_rects {
+                        rect.1 -= dy;
+                        rect.3 -= dy;
+                    }
+                    let (top, bottom) = if dy > 0.0 { (height - dy, height) } else { (0.0, -dy) };
+                    self.paint_band(rt, &resources, top, bottom, primary_line);
+                } else {
+                    self.caret_rects.clear();
+                    self.paint_band(rt, &resources, 0.0, height, primary_line);
+                }
             }
-            y += LINE_SPACE;
+            Damage::None | Damage::Full => {
+                self.caret_rects.clear();
+                self.paint_band(rt, &resources, 0.0, height, primary_line);
+            }
+        }
+        if let Some(ref palette) = self.palette {
+            palette.paint(rt, &self.dwrite_factory, &self.theme, width);
+        }
+        if let Some(ref settings) = self.settings {
+            settings.paint(rt, &self.dwrite_factory, &self.theme, width);
         }
+        unsafe { set_translation(rt.get_raw(), (0.0, 0.0)) };
         self.resources = Some(resources);
     }
 
@@ -122,14 +264,26 @@ impl Widget for EditView {
         _ctx: &mut LayoutCtx) -> LayoutResult
     {
         let size = bc.constrain((0.0, 0.0));
+        if size != self.size {
+            self.damage = Damage::Full;
+        }
         self.size = size;
         self.update_viewport();
         LayoutResult::Size(size)
     }
 
-    fn mouse(&mut self, event: &MouseEvent, _ctx: &mut HandlerCtx) -> bool { 
+    fn mouse(&mut self, event: &MouseEvent, ctx: &mut HandlerCtx) -> bool { 
         let MouseEvent { x, y, mods: _, which, count } = *event;
+        if (self.palette.is_some() || self.settings.is_some()) && count > 0 {
+            // Clicking anywhere dismisses the overlays.
+            self.palette = None;
+            self.settings = None;
+            self.invalidate(ctx);
+            return true;
+        }
         if which == MouseButton::Left && count == 1 {
+            self.blink.reset();
+            self.invalidate_carets(ctx);
             let (line, col) = self.xy_to_line_col(x, y);
             let params = json!({
                 "ty": "point_select",
@@ -145,9 +299,11 @@ impl Widget for EditView {
         if let Some(cmd) = payload.downcast_ref::<EditViewCommands>() {
             match cmd {
                 EditViewCommands::ViewId(view_id) => {
-                    self.view_id = Some(view_id.to_string());
+                    self.show_document(view_id);
                     self.viewport = 0..0; // zorch viewport
                     self.update_viewport();
+                    self.report_status(ctx);
+                    self.invalidate(ctx);
 
                     // Fire off the pending notifications
                     let pending = mem::replace(&mut self.pending, Vec::new());
@@ -155,52 +311,128 @@ impl Widget for EditView {
                         let (method, params) = notification;
                         self.send_edit_cmd(&method, &params);
                     }
+                    self.go_to_pending(ctx);
+                }
+                EditViewCommands::Close(view_id) => {
+                    self.documents.remove(view_id);
+                    self.pending_go_to.remove(view_id);
+                    if self.view_id.as_ref() == Some(view_id) {
+                        self.view_id = None;
+                        self.line_cache = LineCache::new();
+                        self.scroll_offset = 0.0;
+                        self.pristine = true;
+                        self.invalidate(ctx);
+                    }
                 }
-                EditViewCommands::ApplyUpdate(update) => {
-                    self.apply_update(&update);
-                    ctx.invalidate();
+                EditViewCommands::Recover(sender) => {
+                    self.stash_document();
+                    let texts = self.documents.drain()
+                        .filter(|&(_, ref doc)| !doc.pristine)
+                        .filter_map(|(view_id, doc)| doc.line_cache.text().map(|text| (view_id, text)))
+                        .collect();
+                    let _ = sender.send(texts);
+                    self.scroll_offset = 0.0;
+                    self.pristine = true;
+                    self.invalidate(ctx);
                 }
-                EditViewCommands::ScrollTo(line) => {
-                    self.scroll_to(*line);
-                    ctx.invalidate();
+                EditViewCommands::ApplyUpdate(view_id, update) => {
+                    if self.view_id.as_ref() == Some(view_id) {
+                        self.apply_update(&update);
+                        self.blink.reset();
+                        self.report_status(ctx);
+                        ctx.invalidate();
+                        self.go_to_pending(ctx);
+                    } else {
+                        let doc = self.documents.entry(view_id.clone()).or_insert_with(Document::new);
+                        doc.line_cache.apply_update(update);
+                        if let Some(pristine) = update["pristine"].as_bool() {
+                            doc.pristine = pristine;
+                        }
+                    }
+                }
+                EditViewCommands::ScrollTo(view_id, line) => {
+                    if self.view_id.as_ref() == Some(view_id) {
+                        let old_offset = self.scroll_offset;
+                        self.scroll_to(*line);
+                        self.invalidate_scroll(ctx, old_offset);
+                    } else {
+                        let y = self.line_to_content_y(*line);
+                        let height = self.size.1;
+                        if let Some(doc) = self.documents.get_mut(view_id) {
+                            doc.scroll_offset = scroll_offset_showing(doc.scroll_offset, y, height);
+                        }
+                    }
                 }
                 EditViewCommands::Core(core) => {
                     self.core = core.clone();
                 }
-                EditViewCommands::Undo => {
-                    self.send_action("undo");
+                EditViewCommands::Theme(theme) => {
+                    self.theme = theme.clone();
+                    self.invalidate(ctx);
+                }
+                EditViewCommands::Font(face, size) => {
+                    // The core's default font isn't usually installed on Windows.
+                    if self.font_installed(face) {
+                        self.font_face = face.clone();
+                    }
+                    self.font_size = *size;
+                    *self.shared_font.lock().unwrap() = (self.font_face.clone(), self.font_size);
+                    self.line_space = self.measure_line_space();
+                    self.constrain_scroll();
+                    self.update_viewport();
+                    self.invalidate(ctx);
+                }
+                EditViewCommands::CaretShape(shape) => {
+                    self.caret_style.shape = *shape;
+                    self.invalidate(ctx);
                 }
-                EditViewCommands::Redo => {
-                    self.send_action("redo");
+                EditViewCommands::CaretWidth(width) => {
+                    self.caret_style.width = *width;
+                    self.invalidate(ctx);
                 }
-                EditViewCommands::UpperCase => {
-                    self.send_action("uppercase");
+                EditViewCommands::ToggleCaretBlink => {
+                    let enabled = !self.blink.is_enabled();
+                    self.blink.set_enabled(enabled);
+                    self.invalidate_carets(ctx);
                 }
-                EditViewCommands::LowerCase => {
-                    self.send_action("lowercase");
+                EditViewCommands::Tick => {
+                    if self.has_focus && self.blink.tick() {
+                        self.invalidate_carets(ctx);
+                    }
+                }
+                EditViewCommands::Focus(has_focus) => {
+                    self.has_focus = *has_focus;
+                    self.blink.reset();
+                    self.invalidate_carets(ctx);
                 }
-                EditViewCommands::Transpose => {
-                    self.send_action("transpose");
+                EditViewCommands::Keymap(keymap) => {
+                    self.keymap = keymap.clone();
+                    self.pending_keys.clear();
                 }
-                EditViewCommands::AddCursorAbove => {
-                    // Note: some subtlety around find, the escape key cancels it, but the menu
-                    // shouldn't.
-                    self.send_action("add_selection_above");
+                EditViewCommands::ShowPalette(items) => {
+                    self.palette = Some(Palette::new(items.clone()));
+                    self.pending_keys.clear();
+                    self.invalidate(ctx);
                 }
-                EditViewCommands::AddCursorBelow => {
-                    // Note: some subtlety around find, the escape key cancels it, but the menu
-                    // shouldn't.
-                    self.send_action("add_selection_below");
+                EditViewCommands::GoToLine => {
+                    let hint = format!("Line (1-{}), line:column, or +/- lines from the caret",
+                        self.line_cache.height());
+                    self.palette = Some(Palette::go_to_line(hint));
+                    self.pending_keys.clear();
+                    self.invalidate(ctx);
                 }
-                EditViewCommands::SingleSelection => {
-                    // Note: some subtlety around find, the escape key cancels it, but the menu
-                    // shouldn't.
-                    self.send_action("cancel_operation");
+                EditViewCommands::GoTo(view_id, line, col) => {
+                    self.pending_go_to.insert(view_id.clone(), (*line, *col));
+                    if self.view_id.as_ref() == Some(view_id) {
+                        self.go_to_pending(ctx);
+                    }
                 }
-                EditViewCommands::SelectAll => {
-                    // Note: some subtlety around find, the escape key cancels it, but the menu
-                    // shouldn't.
-                    self.send_action("select_all");
+                EditViewCommands::ShowSettings(title, config, domain, language) => {
+                    self.palette = None;
+                    self.settings = Some(SettingsDialog::new(title.clone(), config.clone(),
+                        *domain, language.clone()));
+                    self.pending_keys.clear();
+                    self.invalidate(ctx);
                 }
             }
         }
@@ -208,6 +440,14 @@ impl Widget for EditView {
     }
 
     fn key(&mut self, event: &KeyEvent, ctx: &mut HandlerCtx) -> bool {
+        if self.settings.is_some() {
+            return self.settings_key(event, ctx);
+        }
+        if self.palette.is_some() {
+            return self.palette_key(event, ctx);
+        }
+        self.blink.reset();
+        self.invalidate_carets(ctx);
         match event.key {
             KeyVariant::Vkey(vk) => {
                 return self.keydown(vk, event.mods, ctx)
@@ -221,10 +461,14 @@ impl Widget for EditView {
 }
 
 impl EditView {
-    pub fn new() -> EditView {
-        EditView {
+    /// Creates an edit view. It keeps `shared_font` up to date with the
+    /// font it draws with.
+    pub fn new(shared_font: SharedFont, keymap: Arc<Keymap>) -> EditView {
+        *shared_font.lock().unwrap() = (DEFAULT_FONT_FACE.to_owned(), DEFAULT_FONT_SIZE);
+        let mut view = EditView {
             view_id: Default::default(),
             line_cache: LineCache::new(),
+            documents: HashMap::new(),
             dwrite_factory: directwrite::Factory::new().unwrap(),
             resources: None,
             scroll_offset: 0.0,
@@ -232,7 +476,27 @@ impl EditView {
             viewport: 0..0,
             core: Default::default(),
             pending: Default::default(),
-        }
+            theme: Default::default(),
+            font_face: DEFAULT_FONT_FACE.to_owned(),
+            font_size: DEFAULT_FONT_SIZE,
+            line_space: DEFAULT_LINE_SPACE,
+            shared_font,
+            caret_style: Default::default(),
+            blink: Blink::new(),
+            has_focus: true,
+            caret_rects: Vec::new(),
+            damage: Damage::Full,
+            pristine: true,
+            status: Default::default(),
+            keymap,
+            pending_keys: Vec::new(),
+            suppress_char: false,
+            palette: None,
+            settings: None,
+            pending_go_to: HashMap::new(),
+        };
+        view.line_space = view.measure_line_space();
+        view
     }
 
     pub fn ui(self, ctx: &mut Ui) -> Id {
@@ -242,18 +506,161 @@ impl EditView {
     fn create_resources(&mut self, p: &mut PaintCtx) -> Resources {
         let rt = p.render_target();
         let text_format = TextFormat::create(&self.dwrite_factory)
-            .with_family("Consolas")
-            .with_size(15.0)
+            .with_family(&self.font_face)
+            .with_size(self.font_size)
             .build()
             .unwrap();
+        let theme = &self.theme;
         Resources {
-            fg: SolidColorBrush::create(rt).with_color(0xf0f0ea).build().unwrap(),
-            bg: SolidColorBrush::create(rt).with_color(0x272822).build().unwrap(),
-            sel: SolidColorBrush::create(rt).with_color(0x49483e).build().unwrap(),
+            fg: solid_brush(rt, theme.foreground),
+            bg: solid_brush(rt, theme.background),
+            sel: solid_brush(rt, theme.selection),
+            find: solid_brush(rt, theme.find_highlight),
+            annotation: solid_brush(rt, theme.annotation),
+            caret: solid_brush(rt, theme.caret),
+            secondary_caret: solid_brush(rt, theme.secondary_caret),
+            line_highlight: solid_brush(rt, theme.line_highlight),
             text_format: text_format,
         }
     }
 
+    /// The line spacing for the current font: its line height, rounded up
+    /// to whole px so that lines can be blitted without seams.
+    fn measure_line_space(&self) -> f32 {
+        let format = TextFormat::create(&self.dwrite_factory)
+            .with_family(&self.font_face)
+            .with_size(self.font_size)
+            .build();
+        format.ok()
+            .and_then(|format| textline::line_height(&self.dwrite_factory, &format))
+            .map_or(DEFAULT_LINE_SPACE, f32::ceil)
+    }
+
+    fn font_installed(&self, face: &str) -> bool {
+        FontCollection::system_font_collection(&self.dwrite_factory, false)
+            .ok()
+            .and_then(|fonts| fonts.find_family_by_name(face))
+            .is_some()
+    }
+
+    /// Requests a full repaint.
+    fn invalidate(&mut self, ctx: &mut HandlerCtx) {
+        self.damage = Damage::Full;
+        ctx.invalidate();
+    }
+
+    /// Requests a repaint of just the carets, unless more is already pending.
+    fn invalidate_carets(&mut self, ctx: &mut HandlerCtx) {
+        let lines = self.caret_rects.iter().map(|&(line, _)| line).collect::<Vec<_>>();
+        self.damage.add_carets(&lines);
+        ctx.invalidate();
+    }
+
+    /// Requests a repaint after the scroll offset changed from `old_offset`.
+    ///
+    /// Small scrolls move the existing pixels and only paint what's exposed.
+    fn invalidate_scroll(&mut self, ctx: &mut HandlerCtx, old_offset: f32) {
+        let dy = self.scroll_offset - old_offset;
+        self.damage.add_scroll(dy, self.size.1 / 2.0);
+        ctx.invalidate();
+    }
+
+    /// Adds changed lines to the damage.
+    fn damage_lines(&mut self, lines: &[Range<usize>]) {
+        if self.damage == Damage::Carets {
+            // Keep the pending caret repaint by repainting their lines too.
+            let mut lines = lines.to_vec();
+            lines.extend(self.caret_rects.iter().map(|&(line, _)| line..line + 1));
+            self.damage.add_lines(&lines);
+        } else {
+            self.damage.add_lines(lines);
+        }
+    }
+
+    /// Repaints the horizontal band `top..bottom` (in px) of the view,
+    /// clipped to the band, and updates the caret rectangles of its lines.
+    fn paint_band<R: RenderTarget>(&mut self, rt: &mut R, resources: &Resources, top: f32,
+        bottom: f32, primary_line: Option<usize>)
+    {
+        let rect = (0.0, top, self.size.0, bottom);
+        rt.push_axis_aligned_clip(RectF::from(rect), AntialiasMode::Aliased);
+        rt.fill_rectangle(rect, &resources.bg);
+
+        let first_line = self.y_to_line(top);
+        let last_line = min(self.y_to_line(bottom) + 1, self.line_cache.height());
+        self.caret_rects.retain(|&(line, _)| line < first_line || line >= last_line);
+
+        let mut textlines = Vec::new();
+        let mut y = self.line_to_content_y(first_line) - self.scroll_offset;
+        for line_num in first_line..last_line {
+            if let Some(textline) = self.get_text_line(line_num) {
+                self.paint_line_bg(rt, resources, &textline, y);
+                for rect in textline.cursor_rects(LEFT_PAD, y, &self.caret_style) {
+                    self.caret_rects.push((line_num, rect));
+                }
+                textlines.push((line_num, y, textline));
+            }
+            y += self.line_space;
+        }
+        for (line_num, y, textline) in textlines {
+            self.paint_line_fg(rt, resources, &textline, line_num, y, primary_line);
+        }
+        rt.pop_axis_aligned_clip();
+    }
+
+    /// Paints the background of a line: caret line highlight and selections.
+    fn paint_line_bg<R: RenderTarget>(&self, rt: &mut R, resources: &Resources,
+        textline: &TextLine, y: f32)
+    {
+        if textline.has_cursor() {
+            let rect = (0.0, y, self.size.0, y + self.line_space);
+            rt.fill_rectangle(rect, &resources.line_highlight);
+        }
+        textline.draw_bg(rt, LEFT_PAD, y, |ty| match *ty {
+            AnnotationType::Selection => &resources.sel,
+            AnnotationType::Find => &resources.find,
+            AnnotationType::Other(_) => &resources.annotation,
+        });
+    }
+
+    /// Paints the text and carets of a line.
+    fn paint_line_fg<R: RenderTarget>(&self, rt: &mut R, resources: &Resources,
+        textline: &TextLine, line_num: usize, y: f32, primary_line: Option<usize>)
+    {
+        textline.draw_text(rt, LEFT_PAD, y, &resources.fg);
+        if self.blink.is_visible() || !self.has_focus {
+            let primary = if primary_line == Some(line_num) { Some(0) } else { None };
+            textline.draw_cursor(rt, LEFT_PAD, y, &self.caret_style, primary, !self.has_focus,
+                &resources.caret, &resources.secondary_caret, &resources.bg);
+        }
+    }
+
+    /// Makes the document of `view_id` the shown one.
+    fn show_document(&mut self, view_id: &str) {
+        if self.view_id.as_ref().map(String::as_str) == Some(view_id) {
+            return;
+        }
+        self.stash_document();
+        let doc = self.documents.remove(view_id).unwrap_or_else(Document::new);
+        self.line_cache = doc.line_cache;
+        self.scroll_offset = doc.scroll_offset;
+        self.pristine = doc.pristine;
+        self.view_id = Some(view_id.to_owned());
+        self.caret_rects.clear();
+    }
+
+    /// Moves the shown document to `documents`, leaving none shown.
+    fn stash_document(&mut self) {
+        if let Some(view_id) = self.view_id.take() {
+            let doc = Document {
+                line_cache: mem::replace(&mut self.line_cache, LineCache::new()),
+                scroll_offset: self.scroll_offset,
+                pristine: self.pristine,
+            };
+            self.documents.insert(view_id, doc);
+        }
+    }
+
     pub fn rebuild_resources(&mut self) {
         self.resources = None;
     }
@@ -266,16 +673,45 @@ impl EditView {
     fn get_text_line(&self, line_num: usize) -> Option<TextLine> {
         self.line_cache.get_line(line_num).map(|line| {
             let format = &self.resources.as_ref().unwrap().text_format;
-            TextLine::create_from_line(&line, &self.dwrite_factory, format)
+            let annotations = self.line_cache.annotations_for_line(line_num);
+            TextLine::create_from_line(&line, annotations, &self.dwrite_factory, format,
+                self.line_space)
         })
     }
 
     pub fn apply_update(&mut self, update: &Value) {
-        self.line_cache.apply_update(update);
+        let old_offset = self.scroll_offset;
+        let changed = self.line_cache.apply_update(update);
+        if let Some(pristine) = update["pristine"].as_bool() {
+            self.pristine = pristine;
+        }
         self.constrain_scroll();
+        if self.scroll_offset != old_offset {
+            self.damage = Damage::Full;
+        } else {
+            self.damage_lines(&changed);
+        }
+    }
+
+    /// Sends the caret and document status to listeners, if it changed.
+    fn report_status(&mut self, ctx: &mut HandlerCtx) {
+        let status = ViewStatus {
+            position: self.line_cache.primary_cursor_position()
+                .map(|(line, col)| (line + 1, col + 1)),
+            selections: self.line_cache.cursor_count(),
+            line_ending: self.line_cache.line_ending(),
+            pristine: self.pristine,
+        };
+        if status != self.status {
+            self.status = status.clone();
+            ctx.send_event(status);
+        }
     }
 
     pub fn char(&mut self, ch: u32, _mods: u32) {
+        if mem::replace(&mut self.suppress_char, false) {
+            return;
+        }
         if let Some(c) = ::std::char::from_u32(ch) {
             if ch >= 0x20 {
                 // Don't insert control characters
@@ -298,11 +734,6 @@ impl EditView {
 
             let core = core.unwrap();
             core.lock().unwrap().send_notification("edit", &edit_params);
-            // NOTE: For debugging, could be replaced by trace logging
-            // println!("fe->core: {}", json!({
-            //     "method": method,
-            //     "params": params,
-            // }));
         } else {
             self.pending.push((method.to_owned(), params.clone()));
         }
@@ -314,137 +745,120 @@ impl EditView {
     }
 
     pub fn keydown(&mut self, vk_code: i32, mods: u32, ctx: &mut HandlerCtx) -> bool {
-        // Handle special keys here
-        match vk_code {
-            VK_RETURN => {
-                // TODO: modifiers are variants of open
-                self.send_action("insert_newline");
-            }
-            VK_TAB => {
-                // TODO: modified versions
-                self.send_action("insert_tab");
+        self.suppress_char = false;
+        if keys::is_modifier(vk_code) {
+            return false;
+        }
+        let chord = KeyChord::new(mods, vk_code);
+        self.pending_keys.push(chord);
+        let context = self.key_context();
+        let target = match self.keymap.lookup(&self.pending_keys, &context) {
+            Lookup::Found(target) => Some(target.clone()),
+            Lookup::Prefix => {
+                self.suppress_char = chord.is_printable();
+                return true;
             }
-            VK_UP => {
-                if mods == M_CTRL {
-                    self.scroll_offset -= LINE_SPACE;
-                    self.constrain_scroll();
-                    self.update_viewport();
-                    ctx.invalidate();
-                } else {
-                    let action = if mods == M_CTRL | M_ALT {
-                        "add_selection_above"
-                    } else {
-                        s(mods, "move_up", "move_up_and_modify_selection")
-                    };
-                    // TODO: swap line up is ctrl + shift
-                    self.send_action(action);
-                }
+            Lookup::None => None,
+        };
+        let sequence = mem::replace(&mut self.pending_keys, Vec::new());
+        match target {
+            Some(Target::Edit(method)) => self.send_action(&method),
+            Some(Target::Command(name)) => ctx.send_event(RunCommand(name)),
+            // The rest of a sequence that isn't bound to anything is dropped.
+            None if sequence.len() > 1 => (),
+            None if mods == M_CTRL && (vk_code == VK_UP || vk_code == VK_DOWN) => {
+                // Scrolling without moving the caret isn't an edit action.
+                let old_offset = self.scroll_offset;
+                let line_space = self.line_space;
+                self.scroll_offset += if vk_code == VK_UP { -line_space } else { line_space };
+                self.constrain_scroll();
+                self.update_viewport();
+                self.invalidate_scroll(ctx, old_offset);
             }
-            VK_DOWN => {
-                if mods == M_CTRL {
-                    self.scroll_offset += LINE_SPACE;
-                    self.constrain_scroll();
-                    self.update_viewport();
-                    ctx.invalidate();
-                } else {
-                    let action = if mods == M_CTRL | M_ALT {
-                        "add_selection_below"
-                    } else {
-                        s(mods, "move_down", "move_down_and_modify_selection")
-                    };
-                    self.send_action(action);
+            None => return false,
+        }
+        self.suppress_char = chord.is_printable();
+        true
+    }
+
+    /// Handles a key while the command palette is shown.
+    fn palette_key(&mut self, event: &KeyEvent, ctx: &mut HandlerCtx) -> bool {
+        let outcome = {
+            let palette = self.palette.as_mut().unwrap();
+            match event.key {
+                KeyVariant::Vkey(vk) => palette.keydown(vk, event.mods),
+                KeyVariant::Char(ch) => {
+                    palette.insert(ch as u32);
+                    PaletteKey::Continue
                 }
             }
-            VK_LEFT => {
-                // TODO: there is a subtle distinction between alt and ctrl
-                let action = if (mods & (M_ALT | M_CTRL)) != 0 {
-                    s(mods, "move_word_left", "move_word_left_and_modify_selection")
-                } else {
-                    s(mods, "move_left", "move_left_and_modify_selection")
-                };
-                self.send_action(action);
-            }
-            VK_RIGHT => {
-                // TODO: there is a subtle distinction between alt and ctrl
-                let action = if (mods & (M_ALT | M_CTRL)) != 0 {
-                    s(mods, "move_word_right", "move_word_right_and_modify_selection")
-                } else {
-                    s(mods, "move_right", "move_right_and_modify_selection")
-                };
-                self.send_action(action);
-            }
-            VK_PRIOR => {
-                self.send_action(s(mods, "scroll_page_up",
-                    "page_up_and_modify_selection"));
-            }
-            VK_NEXT => {
-                self.send_action(s(mods, "scroll_page_down",
-                    "page_down_and_modify_selection"));
-            }
-            VK_HOME => {
-                let action = if (mods & M_CTRL) != 0 {
-                    s(mods, "move_to_beginning_of_document",
-                        "move_to_beginning_of_document_and_modify_selection")
-                } else {
-                    s(mods, "move_to_left_end_of_line",
-                        "move_to_left_end_of_line_and_modify_selection")
-                };
-                self.send_action(action);
-            }
-            VK_END => {
-                let action = if (mods & M_CTRL) != 0 {
-                    s(mods, "move_to_end_of_document",
-                        "move_to_end_of_document_and_modify_selection")
-                } else {
-                    s(mods, "move_to_right_end_of_line",
-                        "move_to_right_end_of_line_and_modify_selection")
-                };
-                self.send_action(action);
-            }
-            VK_ESCAPE => {
-                self.send_action("cancel_operation");
-            }
-            VK_BACK => {
-                let action = if (mods & M_CTRL) != 0 {
-                    // should be "delete to beginning of paragraph" but not supported
-                    s(mods, "delete_word_backward", "delete_to_beginning_of_line")
-                } else {
-                    "delete_backward"
-                };
-                self.send_action(action);
-            }
-            VK_DELETE => {
-                let action = if (mods & M_CTRL) != 0 {
-                    s(mods, "delete_word_forward", "delete_to_end_of_paragraph")
-                } else {
-                    // TODO: shift-delete should be "delete line"
-                    "delete_forward"
-                };
-                self.send_action(action);
+        };
+        match outcome {
+            PaletteKey::Continue => (),
+            PaletteKey::Close => self.palette = None,
+            PaletteKey::Run(name) => {
+                self.palette = None;
+                ctx.send_event(PaletteChoice(name));
             }
-            VK_OEM_4 => {
-                // generally '[' key, but might vary on non-US keyboards
-                if mods == M_CTRL {
-                    self.send_action("outdent");
-                } else {
-                    return false
+            PaletteKey::GoTo(text) => {
+                let current = self.line_cache.primary_cursor_line().unwrap_or(0);
+                match parse_line_target(&text, current, self.line_cache.height()) {
+                    Ok((line, col)) => {
+                        self.palette = None;
+                        self.go_to(line, col, ctx);
+                    }
+                    Err(e) => self.palette.as_mut().unwrap().set_error(e),
                 }
             }
-            VK_OEM_6 => {
-                // generally ']' key, but might vary on non-US keyboards
-                if mods == M_CTRL {
-                    self.send_action("indent");
-                } else {
-                    return false
+        }
+        self.invalidate(ctx);
+        true
+    }
+
+    /// Handles a key while the settings dialog is shown.
+    fn settings_key(&mut self, event: &KeyEvent, ctx: &mut HandlerCtx) -> bool {
+        let outcome = {
+            let settings = self.settings.as_mut().unwrap();
+            match event.key {
+                KeyVariant::Vkey(vk) => settings.keydown(vk, event.mods),
+                KeyVariant::Char(ch) => {
+                    settings.insert(ch as u32);
+                    DialogKey::Continue
                 }
             }
-            _ => {
-                return false
+        };
+        match outcome {
+            DialogKey::Continue => (),
+            DialogKey::Close => self.settings = None,
+            DialogKey::Apply(domain, changes) => {
+                let missing_font = changes["font_face"].as_str()
+                    .filter(|face| !self.font_installed(face))
+                    .map(str::to_owned);
+                match missing_font {
+                    Some(face) => {
+                        let error = format!("The font {:?} is not installed", face);
+                        self.settings.as_mut().unwrap().set_error(error);
+                    }
+                    None => {
+                        self.settings = None;
+                        ctx.send_event(ApplySettings(domain, changes));
+                    }
+                }
             }
         }
+        self.invalidate(ctx);
         true
     }
 
+    /// The state that the contexts of key bindings are checked against.
+    fn key_context(&self) -> KeyContext {
+        KeyContext {
+            selection: self.line_cache.has_annotation(&AnnotationType::Selection),
+            multiple_carets: self.line_cache.cursor_count() > 1,
+            find: self.line_cache.has_annotation(&AnnotationType::Find),
+        }
+    }
+
     pub fn mouse_wheel(&mut self, delta: i32, _mods: u32) {
         // TODO: scale properly, taking SPI_GETWHEELSCROLLLINES into account
         let scroll_scaling = 0.5;
@@ -454,7 +868,7 @@ impl EditView {
     }
 
     fn constrain_scroll(&mut self) {
-        let max_scroll = TOP_PAD + LINE_SPACE *
+        let max_scroll = TOP_PAD + self.line_space *
             (self.line_cache.height().saturating_sub(1)) as f32;
         if self.scroll_offset < 0.0 {
             self.scroll_offset = 0.0;
@@ -465,7 +879,7 @@ impl EditView {
 
     // Takes y in screen-space px.
     fn y_to_line(&self, y: f32) -> usize {
-        let mut line = (y + self.scroll_offset - TOP_PAD) / LINE_SPACE;
+        let mut line = (y + self.scroll_offset - TOP_PAD) / self.line_space;
         if line < 0.0 { line = 0.0; }
         let line = line.floor() as usize;
         min(line, self.line_cache.height())
@@ -486,12 +900,12 @@ impl EditView {
 
     /// Convert line number to y coordinate in content space.
     fn line_to_content_y(&self, line: usize) -> f32 {
-        TOP_PAD + (line as f32) * LINE_SPACE
+        TOP_PAD + (line as f32) * self.line_space
     }
 
     fn update_viewport(&mut self) {
         let first_line = self.y_to_line(0.0);
-        let last_line = first_line + ((self.size.1 / LINE_SPACE).floor() as usize) + 1;
+        let last_line = first_line + ((self.size.1 / self.line_space).floor() as usize) + 1;
         let viewport = first_line..last_line;
         if viewport != self.viewport {
             self.viewport = viewport;
@@ -501,16 +915,213 @@ impl EditView {
 
     pub fn scroll_to(&mut self, line: usize) {
         let y = self.line_to_content_y(line);
-        let bottom_slop = 20.0;
-        if y < self.scroll_offset {
-            self.scroll_offset = y;
-        } else if y > self.scroll_offset + self.size.1 - bottom_slop {
-            self.scroll_offset = y - (self.size.1 - bottom_slop)
+        self.scroll_offset = scroll_offset_showing(self.scroll_offset, y, self.size.1);
+    }
+
+    /// Goes to the position pending for the shown document, if its line is
+    /// loaded. Otherwise scrolls to it, so that the core sends it.
+    fn go_to_pending(&mut self, ctx: &mut HandlerCtx) {
+        let view_id = match self.view_id.clone() {
+            Some(view_id) => view_id,
+            None => return,
+        };
+        let (line, col) = match self.pending_go_to.get(&view_id) {
+            Some(&position) => position,
+            None => return,
+        };
+        let height = self.line_cache.height();
+        if height == 0 {
+            // Nothing is loaded yet.
+            return;
+        }
+        let line = line.min(height - 1);
+        if self.line_cache.get_line(line).is_none() {
+            let old_offset = self.scroll_offset;
+            self.scroll_to(line);
+            self.update_viewport();
+            self.invalidate_scroll(ctx, old_offset);
+            return;
+        }
+        self.pending_go_to.remove(&view_id);
+        self.go_to(line, col, ctx);
+    }
+
+    /// Moves the caret to `line` and `col` (0-based, in chars, clamped to
+    /// the end of the line) and scrolls it into view.
+    fn go_to(&mut self, line: usize, col: usize, ctx: &mut HandlerCtx) {
+        // The core wants a utf8 offset; lines not in the cache are taken to
+        // be ASCII.
+        let col = match self.line_cache.get_line(line) {
+            Some(line) => {
+                let text = line.text();
+                let end = text.find(|c: char| c == '\n' || c == '\r').unwrap_or(text.len());
+                text[..end].char_indices().nth(col).map_or(end, |(offset, _)| offset)
+            }
+            None => col,
+        };
+        self.send_edit_cmd("gesture", &json!({
+            "ty": "point_select",
+            "line": line,
+            "col": col,
+        }));
+        let old_offset = self.scroll_offset;
+        self.scroll_to(line);
+        self.update_viewport();
+        self.invalidate_scroll(ctx, old_offset);
+    }
+}
+
+/// Parses what was typed to go to a line: a 1-based `line` or `line:col`, or
+/// `+n`/`-n` lines from the `current` one. Returns the 0-based line and
+/// column, checked against the `height` of the document.
+fn parse_line_target(text: &str, current: usize, height: usize)
+    -> Result<(usize, usize), String>
+{
+    let text = text.trim();
+    if text.is_empty() {
+        return Err("Type a line number".to_owned());
+    }
+    let number = |s: &str| {
+        let s = s.trim();
+        s.parse::<usize>().map_err(|_| format!("{:?} is not a number", s))
+    };
+    // Takes the 0-based line as a u128, so it can be shown even when it
+    // doesn't fit in a usize.
+    let past_end = |line: u128| format!("Line {} is past the last line ({})", line + 1, height);
+    let (line, col) = if text.starts_with('+') {
+        let n = number(&text[1..])?;
+        match current.checked_add(n) {
+            Some(line) => (line, 0),
+            None => return Err(past_end(current as u128 + n as u128)),
+        }
+    } else if text.starts_with('-') {
+        let n = number(&text[1..])?;
+        if n > current {
+            return Err(format!("There are only {} lines above the caret", current));
         }
+        (current - n, 0)
+    } else {
+        let mut parts = text.splitn(2, ':');
+        let line = number(parts.next().unwrap())?;
+        let col = match parts.next() {
+            Some(col) => number(col)?,
+            None => 1,
+        };
+        if line == 0 || col == 0 {
+            return Err("Lines and columns start at 1".to_owned());
+        }
+        (line - 1, col - 1)
+    };
+    if line >= height {
+        return Err(past_end(line as u128));
+    }
+    Ok((line, col))
+}
+
+/// The scroll offset closest to `offset` that shows content y `y` in a
+/// view `height` px tall.
+fn scroll_offset_showing(offset: f32, y: f32, height: f32) -> f32 {
+    let bottom_slop = 20.0;
+    if y < offset {
+        y
+    } else if y > offset + height - bottom_slop {
+        y - (height - bottom_slop)
+    } else {
+        offset
     }
 }
 
-// Helper function for choosing between normal and shifted action
-fn s<'a>(mods: u32, normal: &'a str, shifted: &'a str) -> &'a str {
-    if (mods & M_SHIFT) != 0 { shifted } else { normal }
+/// Sets the render target's transform to a translation by `(x, y)`.
+///
+/// druid widgets paint in window coordinates; the edit view paints relative
+/// to its own origin.
+unsafe fn set_translation(rt: *mut ID2D1RenderTarget, (x, y): (f32, f32)) {
+    let matrix = D2D1_MATRIX_3X2_F { matrix: [[1.0, 0.0], [0.0, 1.0], [x, y]] };
+    (*rt).SetTransform(&matrix);
+}
+
+/// Moves the band `top..bottom` (in px, full width, relative to `origin`) of
+/// the render target up by `dy` px (down if negative), by way of an
+/// intermediate bitmap. The render target must be translated to `origin`.
+///
+/// Returns `false` if the copy failed, in which case nothing was drawn.
+unsafe fn blit_vertical(rt: *mut ID2D1RenderTarget, origin: (f32, f32), width: f32, top: f32,
+    bottom: f32, dy: f32) -> bool
+{
+    let rt = &*rt;
+    let (mut dpi_x, mut dpi_y) = (0.0, 0.0);
+    rt.GetDpi(&mut dpi_x, &mut dpi_y);
+    let (scale_x, scale_y) = (dpi_x / 96.0, dpi_y / 96.0);
+    // Unlike drawing, copying isn't affected by the transform.
+    let (x0, y0) = origin;
+    let src = D2D1_RECT_U {
+        left: (x0 * scale_x).round() as u32,
+        top: ((y0 + top) * scale_y).round() as u32,
+        right: ((x0 + width) * scale_x).round() as u32,
+        bottom: ((y0 + bottom) * scale_y).round() as u32,
+    };
+    if src.right <= src.left || src.bottom <= src.top {
+        return true;
+    }
+    let size = D2D1_SIZE_U { width: src.right - src.left, height: src.bottom - src.top };
+    let props = D2D1_BITMAP_PROPERTIES {
+        pixelFormat: rt.GetPixelFormat(),
+        dpiX: dpi_x,
+        dpiY: dpi_y,
+    };
+    let mut bitmap = null_mut();
+    if !SUCCEEDED(rt.CreateBitmap(size, null(), 0, &props, &mut bitmap)) {
+        return false;
+    }
+    // Make sure pending drawing is in the target before copying from it.
+    rt.Flush(null_mut(), null_mut());
+    let dest_point = D2D1_POINT_2U { x: 0, y: 0 };
+    let rt_ptr = rt as *const ID2D1RenderTarget as *mut ID2D1RenderTarget;
+    let ok = SUCCEEDED((*bitmap).CopyFromRenderTarget(&dest_point, rt_ptr, &src));
+    if ok {
+        let dest = D2D1_RECT_F { left: 0.0, top: top - dy, right: width, bottom: bottom - dy };
+        rt.DrawBitmap(bitmap, &dest, 1.0, D2D1_BITMAP_INTERPOLATION_MODE_NEAREST_NEIGHBOR,
+            null());
+    }
+    (*bitmap).Release();
+    ok
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses `text` with the caret on the 6th of 20 lines.
+    fn target(text: &str) -> Result<(usize, usize), String> {
+        parse_line_target(text, 5, 20)
+    }
+
+    #[test]
+    fn line_targets() {
+        assert_eq!(target("12"), Ok((11, 0)));
+        assert_eq!(target(" 12:5 "), Ok((11, 4)));
+        assert_eq!(target("+3"), Ok((8, 0)));
+        assert_eq!(target("-3"), Ok((2, 0)));
+        assert_eq!(target("20"), Ok((19, 0)));
+    }
+
+    #[test]
+    fn bad_line_targets() {
+        let start_at_1 = Err("Lines and columns start at 1".to_owned());
+        assert_eq!(target("0"), start_at_1);
+        assert_eq!(target("5:0"), start_at_1);
+        assert_eq!(target("21"), Err("Line 21 is past the last line (20)".to_owned()));
+        assert_eq!(target("+15"), Err("Line 21 is past the last line (20)".to_owned()));
+        assert_eq!(target("-6"), Err("There are only 5 lines above the caret".to_owned()));
+        assert_eq!(target("x"), Err("\"x\" is not a number".to_owned()));
+        assert_eq!(target(""), Err("Type a line number".to_owned()));
+    }
+
+    #[test]
+    fn line_target_overflow() {
+        let text = format!("+{}", usize::MAX);
+        // 1-based, from the 6th line.
+        let line = usize::MAX as u128 + 6;
+        assert_eq!(target(&text), Err(format!("Line {} is past the last line (20)", line)));
+    }
 }
@@ -0,0 +1,404 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Key bindings: the defaults of the registered commands, and the user's
+//! own from `keymap.json` in the config directory.
+//!
+//! The file holds a JSON array of bindings like
+//!
+//! ```json
+//! [
+//!     { "keys": "Ctrl+K Ctrl+U", "command": "uppercase" },
+//!     { "keys": "Ctrl+D", "edit": "duplicate_line" },
+//!     { "keys": "Escape", "edit": "collapse_selections", "context": "multiple_carets" }
+//! ]
+//! ```
+//!
+//! `keys` is a sequence of chords separated by spaces. `command` names a
+//! registered command, while `edit` can name any edit action of the core.
+//! A binding with a `context` only applies when it holds, or when it
+//! doesn't if it starts with `!`. Bindings later in the file take
+//! precedence over earlier ones, and all of them over the defaults.
+
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::time::SystemTime;
+
+use serde_json::{self, Value};
+
+use commands::{Action, Command, Registry};
+use keys::KeyChord;
+
+pub const KEYMAP_FILE: &str = "keymap.json";
+
+/// A condition on the edit view for a binding to apply.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Context {
+    /// Some selection is not empty.
+    Selection,
+    /// There's more than one caret.
+    MultipleCarets,
+    /// Find results are highlighted.
+    Find,
+}
+
+const CONTEXTS: &[(&str, Context)] = &[
+    ("selection", Context::Selection),
+    ("multiple_carets", Context::MultipleCarets),
+    ("find", Context::Find),
+];
+
+/// The state of the edit view that contexts are checked against.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KeyContext {
+    pub selection: bool,
+    pub multiple_carets: bool,
+    pub find: bool,
+}
+
+impl KeyContext {
+    fn holds(&self, context: Context) -> bool {
+        match context {
+            Context::Selection => self.selection,
+            Context::MultipleCarets => self.multiple_carets,
+            Context::Find => self.find,
+        }
+    }
+}
+
+/// What a binding runs.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Target {
+    /// A registered command that the app runs.
+    Command(String),
+    /// An edit action, which the edit view sends to the core itself.
+    Edit(String),
+}
+
+impl Target {
+    pub fn name(&self) -> &str {
+        match *self {
+            Target::Command(ref name) | Target::Edit(ref name) => name,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Binding {
+    keys: Vec<KeyChord>,
+    target: Target,
+    /// The context and whether it must hold (rather than not hold).
+    context: Option<(Context, bool)>,
+}
+
+/// The result of looking up the keys pressed so far.
+#[derive(Debug, PartialEq)]
+pub enum Lookup<'a> {
+    Found(&'a Target),
+    /// The keys start a longer sequence.
+    Prefix,
+    None,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Keymap {
+    /// In increasing order of precedence.
+    bindings: Vec<Binding>,
+}
+
+impl Keymap {
+    /// The default bindings of the registered commands.
+    pub fn defaults(registry: &Registry) -> Keymap {
+        let mut bindings = Vec::new();
+        for command in registry.commands() {
+            for &key in &command.keys {
+                bindings.push(Binding {
+                    keys: vec![key],
+                    target: target_for(command),
+                    context: None,
+                });
+            }
+        }
+        Keymap { bindings }
+    }
+
+    /// The defaults with the user's bindings from `path` on top, and a
+    /// description of each problem with the file. Invalid bindings are left
+    /// out; a missing file is not a problem.
+    pub fn load(path: Option<&Path>, registry: &Registry) -> (Keymap, Vec<String>) {
+        let mut keymap = Keymap::defaults(registry);
+        let path = match path {
+            Some(path) => path,
+            None => return (keymap, Vec::new()),
+        };
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return (keymap, Vec::new()),
+            Err(e) => return (keymap, vec![format!("could not read {:?}: {}", path, e)]),
+        };
+        let json = match serde_json::from_str::<Value>(&text) {
+            Ok(json) => json,
+            Err(e) => return (keymap, vec![format!("{:?} is not valid JSON: {}", path, e)]),
+        };
+        let entries = match json.as_array() {
+            Some(entries) => entries,
+            None => return (keymap, vec![format!("{:?} should hold an array", path)]),
+        };
+        let problems = keymap.add_bindings(entries, registry);
+        (keymap, problems)
+    }
+
+    /// Adds the bindings in `entries` on top, and returns a description of
+    /// each invalid one, which is left out.
+    fn add_bindings(&mut self, entries: &[Value], registry: &Registry) -> Vec<String> {
+        let mut problems = Vec::new();
+        for (i, entry) in entries.iter().enumerate() {
+            match parse_binding(entry, registry) {
+                Ok(binding) => self.bindings.push(binding),
+                Err(e) => problems.push(format!("{}, binding {}: {}", KEYMAP_FILE, i + 1, e)),
+            }
+        }
+        problems
+    }
+
+    /// Looks up a sequence of keys in the given context.
+    pub fn lookup(&self, keys: &[KeyChord], context: &KeyContext) -> Lookup {
+        let active = self.bindings.iter().rev().filter(|binding| match binding.context {
+            Some((c, holds)) => context.holds(c) == holds,
+            None => true,
+        });
+        let mut found = None;
+        for binding in active {
+            if binding.keys.len() > keys.len() && binding.keys.starts_with(keys) {
+                // Wait for the rest of the sequence.
+                return Lookup::Prefix;
+            }
+            if found.is_none() && binding.keys.as_slice() == keys {
+                found = Some(&binding.target);
+            }
+        }
+        match found {
+            Some(target) => Lookup::Found(target),
+            None => Lookup::None,
+        }
+    }
+
+    /// The keys running the command `name` regardless of context, if any.
+    pub fn keys_for(&self, name: &str) -> Option<&[KeyChord]> {
+        self.bindings.iter().rev()
+            .filter(|binding| binding.context.is_none() && binding.target.name() == name)
+            .map(|binding| binding.keys.as_slice())
+            .find(|&keys| self.bindings.iter().rev()
+                .find(|binding| binding.context.is_none() && binding.keys.as_slice() == keys)
+                .map_or(false, |binding| binding.target.name() == name))
+    }
+
+    /// The text of a menu item running `command`: its title and keys.
+    pub fn menu_text(&self, command: &Command) -> String {
+        match self.keys_for(command.name) {
+            Some(keys) => format!("{}\t{}", command.title, format_keys(keys)),
+            None => command.title.to_owned(),
+        }
+    }
+}
+
+fn target_for(command: &Command) -> Target {
+    match command.action {
+        Action::Edit => Target::Edit(command.name.to_owned()),
+        Action::App(_) | Action::Setting => Target::Command(command.name.to_owned()),
+    }
+}
+
+fn parse_binding(entry: &Value, registry: &Registry) -> Result<Binding, String> {
+    let entry = entry.as_object().ok_or("should be an object")?;
+    if let Some(field) = entry.keys().find(|&field| {
+        !["keys", "command", "edit", "context"].contains(&field.as_str())
+    }) {
+        return Err(format!("unknown field {:?}", field));
+    }
+    let keys = entry.get("keys").and_then(Value::as_str).ok_or("\"keys\" should be a string")?;
+    let keys = parse_keys(keys)?;
+    let target = match (entry.get("command"), entry.get("edit")) {
+        (Some(command), None) => {
+            let name = command.as_str().ok_or("\"command\" should be a string")?;
+            let command = registry.find(name)
+                .ok_or_else(|| format!("unknown command {:?}", name))?;
+            target_for(command)
+        }
+        (None, Some(edit)) => {
+            let method = edit.as_str().ok_or("\"edit\" should be a string")?;
+            if method.is_empty() || !method.chars().all(|c| c.is_ascii_lowercase() || c == '_') {
+                return Err(format!("{:?} is not an edit action", method));
+            }
+            Target::Edit(method.to_owned())
+        }
+        _ => return Err("should have either \"command\" or \"edit\"".to_owned()),
+    };
+    let context = match entry.get("context") {
+        Some(context) => {
+            let context = context.as_str().ok_or("\"context\" should be a string")?;
+            let (name, holds) = if context.starts_with('!') {
+                (&context[1..], false)
+            } else {
+                (context, true)
+            };
+            let &(_, c) = CONTEXTS.iter().find(|&&(n, _)| n == name)
+                .ok_or_else(|| format!("unknown context {:?}", context))?;
+            Some((c, holds))
+        }
+        None => None,
+    };
+    Ok(Binding { keys, target, context })
+}
+
+/// Parses chords separated by spaces, like "Ctrl+K Ctrl+C".
+pub fn parse_keys(s: &str) -> Result<Vec<KeyChord>, String> {
+    let keys = s.split_whitespace().map(KeyChord::parse).collect::<Result<Vec<_>, _>>()?;
+    if keys.is_empty() {
+        return Err("no keys given".to_owned());
+    }
+    Ok(keys)
+}
+
+pub fn format_keys(keys: &[KeyChord]) -> String {
+    keys.iter().map(KeyChord::to_string).collect::<Vec<_>>().join(" ")
+}
+
+/// When the keymap file was last modified, if it exists.
+pub fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The defaults with `bindings` on top, which must all be valid.
+    fn keymap(bindings: Value) -> Keymap {
+        let registry = Registry::new();
+        let mut keymap = Keymap::defaults(&registry);
+        let problems = keymap.add_bindings(bindings.as_array().unwrap(), &registry);
+        assert!(problems.is_empty(), "{:?}", problems);
+        keymap
+    }
+
+    fn lookup<'a>(keymap: &'a Keymap, keys: &str, context: &KeyContext) -> Lookup<'a> {
+        keymap.lookup(&parse_keys(keys).unwrap(), context)
+    }
+
+    fn command(name: &str) -> Target {
+        Target::Command(name.to_owned())
+    }
+
+    fn edit(name: &str) -> Target {
+        Target::Edit(name.to_owned())
+    }
+
+    /// The problem with a single binding, if any.
+    fn problem(binding: Value) -> Option<String> {
+        let registry = Registry::new();
+        let mut keymap = Keymap::default();
+        keymap.add_bindings(&[binding], &registry).pop()
+    }
+
+    #[test]
+    fn defaults() {
+        let keymap = keymap(json!([]));
+        let none = KeyContext::default();
+        assert_eq!(lookup(&keymap, "Ctrl+O", &none), Lookup::Found(&command("open")));
+        assert_eq!(lookup(&keymap, "Ctrl+Z", &none), Lookup::Found(&edit("undo")));
+        assert_eq!(lookup(&keymap, "Ctrl+Shift+F12", &none), Lookup::None);
+    }
+
+    #[test]
+    fn sequences() {
+        let keymap = keymap(json!([{ "keys": "Ctrl+K Ctrl+U", "edit": "uppercase" }]));
+        let none = KeyContext::default();
+        assert_eq!(lookup(&keymap, "Ctrl+K", &none), Lookup::Prefix);
+        assert_eq!(lookup(&keymap, "Ctrl+K Ctrl+U", &none), Lookup::Found(&edit("uppercase")));
+        assert_eq!(lookup(&keymap, "Ctrl+K Ctrl+X", &none), Lookup::None);
+        // A sequence starting with a bound chord takes over that chord.
+        let keymap = self::keymap(json!([{ "keys": "Ctrl+C Ctrl+C", "edit": "uppercase" }]));
+        assert_eq!(lookup(&keymap, "Ctrl+C", &none), Lookup::Prefix);
+    }
+
+    #[test]
+    fn later_bindings_take_precedence() {
+        let keymap = keymap(json!([
+            { "keys": "Ctrl+O", "command": "save" },
+            { "keys": "Ctrl+D", "edit": "duplicate_line" },
+            { "keys": "Ctrl+D", "edit": "uppercase" },
+        ]));
+        let none = KeyContext::default();
+        assert_eq!(lookup(&keymap, "Ctrl+O", &none), Lookup::Found(&command("save")));
+        assert_eq!(lookup(&keymap, "Ctrl+D", &none), Lookup::Found(&edit("uppercase")));
+    }
+
+    #[test]
+    fn contexts() {
+        let keymap = keymap(json!([
+            { "keys": "Escape", "edit": "collapse_selections", "context": "multiple_carets" },
+            { "keys": "Ctrl+D", "edit": "duplicate_line", "context": "!selection" },
+        ]));
+        let none = KeyContext::default();
+        let carets = KeyContext { multiple_carets: true, ..KeyContext::default() };
+        let selection = KeyContext { selection: true, ..KeyContext::default() };
+        assert_eq!(lookup(&keymap, "Escape", &none), Lookup::Found(&edit("cancel_operation")));
+        assert_eq!(lookup(&keymap, "Escape", &carets),
+            Lookup::Found(&edit("collapse_selections")));
+        assert_eq!(lookup(&keymap, "Ctrl+D", &none), Lookup::Found(&edit("duplicate_line")));
+        assert_eq!(lookup(&keymap, "Ctrl+D", &selection), Lookup::None);
+    }
+
+    #[test]
+    fn keys_for_commands() {
+        let keymap = keymap(json!([
+            { "keys": "Ctrl+O", "command": "save" },
+            { "keys": "Ctrl+Alt+X", "command": "cut", "context": "selection" },
+        ]));
+        let keys = |name: &str| keymap.keys_for(name).map(format_keys);
+        assert_eq!(keys("save"), Some("Ctrl+O".to_owned()));
+        // Its only key now runs something else.
+        assert_eq!(keys("open"), None);
+        // Bindings with a context don't show in menus.
+        assert_eq!(keys("cut"), Some("Ctrl+X".to_owned()));
+        assert_eq!(keys("redo"), Some("Ctrl+Shift+Z".to_owned()));
+    }
+
+    #[test]
+    fn bad_bindings() {
+        let problem = |binding| problem(binding).unwrap();
+        assert_eq!(problem(json!({ "keys": "Ctrl+Foo", "edit": "undo" })),
+            "keymap.json, binding 1: unknown key \"Foo\" in \"Ctrl+Foo\"");
+        assert_eq!(problem(json!({ "keys": "Hyper+A", "edit": "undo" })),
+            "keymap.json, binding 1: unknown modifier \"Hyper\" in \"Hyper+A\"");
+        assert_eq!(problem(json!({ "keys": "Ctrl+A", "edit": "undo", "when": "always" })),
+            "keymap.json, binding 1: unknown field \"when\"");
+        assert_eq!(problem(json!({ "keys": "Ctrl+A", "command": "frobnicate" })),
+            "keymap.json, binding 1: unknown command \"frobnicate\"");
+        assert_eq!(problem(json!({ "keys": "Ctrl+A", "edit": "undo", "context": "!focus" })),
+            "keymap.json, binding 1: unknown context \"!focus\"");
+        assert_eq!(problem(json!({ "keys": "Ctrl+A", "edit": "Undo!" })),
+            "keymap.json, binding 1: \"Undo!\" is not an edit action");
+        assert_eq!(problem(json!({ "keys": "Ctrl+A", "edit": "undo", "command": "open" })),
+            "keymap.json, binding 1: should have either \"command\" or \"edit\"");
+        assert_eq!(problem(json!({ "keys": " ", "edit": "undo" })),
+            "keymap.json, binding 1: no keys given");
+        assert_eq!(problem(json!({ "keys": 1, "edit": "undo" })),
+            "keymap.json, binding 1: \"keys\" should be a string");
+        assert_eq!(problem(json!(["Ctrl+A", "undo"])),
+            "keymap.json, binding 1: should be an object");
+    }
+}
@@ -0,0 +1,184 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Key chords, as written in key bindings ("Ctrl+Shift+S").
+
+use std::fmt;
+
+use winapi::um::winuser::*;
+
+use druid_win_shell::window::{M_ALT, M_CTRL, M_SHIFT};
+
+/// A key pressed together with modifiers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct KeyChord {
+    /// `M_CTRL`, `M_ALT` and `M_SHIFT` flags.
+    pub mods: u32,
+    /// The virtual key code.
+    pub vk: i32,
+}
+
+const MODIFIERS: &[(&str, u32)] = &[
+    ("Ctrl", M_CTRL),
+    ("Alt", M_ALT),
+    ("Shift", M_SHIFT),
+];
+
+/// Names of the keys that aren't letters, digits or function keys.
+const KEY_NAMES: &[(&str, i32)] = &[
+    ("Enter", VK_RETURN),
+    ("Tab", VK_TAB),
+    ("Escape", VK_ESCAPE),
+    ("Backspace", VK_BACK),
+    ("Delete", VK_DELETE),
+    ("Insert", VK_INSERT),
+    ("Space", VK_SPACE),
+    ("Up", VK_UP),
+    ("Down", VK_DOWN),
+    ("Left", VK_LEFT),
+    ("Right", VK_RIGHT),
+    ("Home", VK_HOME),
+    ("End", VK_END),
+    ("PageUp", VK_PRIOR),
+    ("PageDown", VK_NEXT),
+    // Generally the bracket keys, but these vary on non-US keyboards.
+    ("[", VK_OEM_4),
+    ("]", VK_OEM_6),
+];
+
+impl KeyChord {
+    pub fn new(mods: u32, vk: i32) -> KeyChord {
+        KeyChord { mods, vk }
+    }
+
+    /// Parses modifiers and a key joined by `+`, like "Ctrl+Alt+Up".
+    /// Names are not case sensitive.
+    pub fn parse(s: &str) -> Result<KeyChord, String> {
+        let parts = s.split('+').map(str::trim).collect::<Vec<_>>();
+        let (key, mods) = parts.split_last().unwrap();
+        let mut chord = KeyChord::new(0, parse_key(key)
+            .ok_or_else(|| format!("unknown key {:?} in {:?}", key, s))?);
+        for name in mods {
+            let &(_, flag) = MODIFIERS.iter().find(|&&(n, _)| n.eq_ignore_ascii_case(name))
+                .ok_or_else(|| format!("unknown modifier {:?} in {:?}", name, s))?;
+            chord.mods |= flag;
+        }
+        Ok(chord)
+    }
+
+    /// Whether the key types a character when pressed with no modifiers
+    /// but Shift.
+    pub fn is_printable(&self) -> bool {
+        let vk = self.vk;
+        self.mods & (M_CTRL | M_ALT) == 0 && (vk == VK_SPACE
+            || (vk >= 0x30 && vk <= 0x39) || (vk >= 0x41 && vk <= 0x5A)
+            || (vk >= VK_OEM_1 && vk <= VK_OEM_8) || vk == VK_OEM_102)
+    }
+}
+
+/// Whether `vk` is one of the modifier keys, which only ever take part in
+/// chords.
+pub fn is_modifier(vk: i32) -> bool {
+    match vk {
+        VK_SHIFT | VK_CONTROL | VK_MENU | VK_LWIN | VK_RWIN
+            | VK_LSHIFT | VK_RSHIFT | VK_LCONTROL | VK_RCONTROL | VK_LMENU | VK_RMENU => true,
+        _ => false,
+    }
+}
+
+fn parse_key(name: &str) -> Option<i32> {
+    if let Some(&(_, vk)) = KEY_NAMES.iter().find(|&&(n, _)| n.eq_ignore_ascii_case(name)) {
+        return Some(vk);
+    }
+    let upper = name.to_ascii_uppercase();
+    let bytes = upper.as_bytes();
+    if bytes.len() == 1 && (bytes[0].is_ascii_uppercase() || bytes[0].is_ascii_digit()) {
+        return Some(bytes[0] as i32);
+    }
+    if upper.starts_with('F') {
+        if let Ok(n) = upper[1..].parse::<i32>() {
+            if n >= 1 && n <= 12 {
+                return Some(VK_F1 + n - 1);
+            }
+        }
+    }
+    None
+}
+
+fn key_name(vk: i32) -> String {
+    if let Some(&(name, _)) = KEY_NAMES.iter().find(|&&(_, v)| v == vk) {
+        return name.to_owned();
+    }
+    let c = vk as u8 as char;
+    if vk < 0x80 && (c.is_ascii_uppercase() || c.is_ascii_digit()) {
+        c.to_string()
+    } else if vk >= VK_F1 && vk <= VK_F12 {
+        format!("F{}", vk - VK_F1 + 1)
+    } else {
+        format!("0x{:02X}", vk)
+    }
+}
+
+impl fmt::Display for KeyChord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &(name, flag) in MODIFIERS {
+            if self.mods & flag != 0 {
+                write!(f, "{}+", name)?;
+            }
+        }
+        write!(f, "{}", key_name(self.vk))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_chords() {
+        assert_eq!(KeyChord::parse("Ctrl+Shift+S"), Ok(KeyChord::new(M_CTRL | M_SHIFT, 0x53)));
+        assert_eq!(KeyChord::parse("alt + left"), Ok(KeyChord::new(M_ALT, VK_LEFT)));
+        assert_eq!(KeyChord::parse("Ctrl+1"), Ok(KeyChord::new(M_CTRL, 0x31)));
+        assert_eq!(KeyChord::parse("f5"), Ok(KeyChord::new(0, VK_F5)));
+        assert_eq!(KeyChord::parse("Ctrl+]"), Ok(KeyChord::new(M_CTRL, VK_OEM_6)));
+        assert_eq!(KeyChord::parse("PageDown"), Ok(KeyChord::new(0, VK_NEXT)));
+    }
+
+    #[test]
+    fn bad_chords() {
+        assert_eq!(KeyChord::parse("Ctrl+Foo"),
+            Err("unknown key \"Foo\" in \"Ctrl+Foo\"".to_owned()));
+        assert_eq!(KeyChord::parse("F13"), Err("unknown key \"F13\" in \"F13\"".to_owned()));
+        assert_eq!(KeyChord::parse("Ctrl+"), Err("unknown key \"\" in \"Ctrl+\"".to_owned()));
+        assert_eq!(KeyChord::parse("Hyper+A"),
+            Err("unknown modifier \"Hyper\" in \"Hyper+A\"".to_owned()));
+    }
+
+    #[test]
+    fn display() {
+        for &s in &["Ctrl+Alt+Shift+Left", "F12", "Ctrl+[", "Shift+Space", "Escape", "9"] {
+            assert_eq!(KeyChord::parse(s).unwrap().to_string(), s);
+        }
+        assert_eq!(KeyChord::parse("shift+ctrl+a").unwrap().to_string(), "Ctrl+Shift+A");
+        assert_eq!(KeyChord::new(M_CTRL, VK_OEM_1).to_string(), "Ctrl+0xBA");
+    }
+
+    #[test]
+    fn printable() {
+        assert!(KeyChord::parse("A").unwrap().is_printable());
+        assert!(KeyChord::parse("Shift+Space").unwrap().is_printable());
+        assert!(!KeyChord::parse("Ctrl+A").unwrap().is_printable());
+        assert!(!KeyChord::parse("Enter").unwrap().is_printable());
+    }
+}
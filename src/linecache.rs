@@ -14,6 +14,7 @@
 
 //! The line cache (text, styles and cursors for a view).
 
+use std::cmp::min;
 use std::mem;
 use std::ops::Range;
 use serde_json::Value;
@@ -32,9 +33,72 @@ pub struct StyleSpan {
     pub range: Range<usize>,
 }
 
+/// The kind of an annotation. Selections and find results are built in,
+/// anything else is provided by plugins.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AnnotationType {
+    Selection,
+    Find,
+    Other(String),
+}
+
+/// A set of annotated ranges of one type, from the `annotations` field of an
+/// update.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Annotation {
+    pub ty: AnnotationType,
+    /// Ranges as (start line, start col, end line, end col), with columns
+    /// in utf-8 code units.
+    pub ranges: Vec<(usize, usize, usize, usize)>,
+    /// Optional per-range data.
+    pub payloads: Option<Vec<Value>>,
+}
+
+impl AnnotationType {
+    fn from_str(s: &str) -> AnnotationType {
+        match s {
+            "selection" => AnnotationType::Selection,
+            "find" => AnnotationType::Find,
+            other => AnnotationType::Other(other.to_owned()),
+        }
+    }
+}
+
+impl Annotation {
+    pub fn from_json(v: &Value) -> Option<Annotation> {
+        let ty = AnnotationType::from_str(v["type"].as_str()?);
+        let mut ranges = Vec::new();
+        for range in v["ranges"].as_array()? {
+            let range = range.as_array()?;
+            if range.len() != 4 {
+                return None;
+            }
+            let get = |i: usize| range[i].as_u64().map(|x| x as usize);
+            ranges.push((get(0)?, get(1)?, get(2)?, get(3)?));
+        }
+        let payloads = v["payloads"].as_array().cloned();
+        Some(Annotation { ty, ranges, payloads })
+    }
+
+    /// The lines touched by this annotation.
+    fn line_ranges<'a>(&'a self) -> impl Iterator<Item = Range<usize>> + 'a {
+        self.ranges.iter().map(|&(start_line, _, end_line, _)| start_line..end_line + 1)
+    }
+}
+
 impl Line {
     pub fn from_json(v: &Value) -> Line {
         let text = v["text"].as_str().unwrap().to_owned();
+        Line::with_text(text, v)
+    }
+
+    /// The line with its carets and styles replaced by the ones in `v`,
+    /// as sent in an `update` op.
+    fn updated(self, v: &Value) -> Line {
+        Line::with_text(self.text, v)
+    }
+
+    fn with_text(text: String, v: &Value) -> Line {
         let mut cursor = Vec::new();
         if let Some(arr) = v["cursor"].as_array() {
             for c in arr {
@@ -77,13 +141,15 @@ impl Line {
 }
 
 pub struct LineCache {
-    lines: Vec<Option<Line>>
+    lines: Vec<Option<Line>>,
+    annotations: Vec<Annotation>,
 }
 
 impl LineCache {
     pub fn new() -> LineCache {
         LineCache {
             lines: Vec::new(),
+            annotations: Vec::new(),
         }
     }
 
@@ -91,39 +157,178 @@ impl LineCache {
         self.lines.push(line);
     }
 
-    pub fn apply_update(&mut self, update: &Value) {
+    /// Applies an update from the core, returning the ranges of lines
+    /// whose contents changed (in new line numbers).
+    ///
+    /// Lines that moved count as changed, as do lines past the new end of
+    /// the document if it got shorter, and lines whose annotations changed.
+    pub fn apply_update(&mut self, update: &Value) -> Vec<Range<usize>> {
         let old_cache = mem::replace(self, LineCache::new());
+        let old_height = old_cache.height();
+        self.annotations = old_cache.annotations;
         let mut old_iter = old_cache.lines.into_iter();
+        let mut old_ix = 0;
+        let mut changed = Vec::new();
         for op in update["ops"].as_array().unwrap() {
             let op_type = &op["op"];
+            let start = self.height();
             if op_type == "ins" {
                 for line in op["lines"].as_array().unwrap() {
                     let line = Line::from_json(line);
                     self.push_opt_line(Some(line));
                 }
+                push_range(&mut changed, start..self.height());
             } else if op_type == "copy" {
-                let n = op["n"].as_u64().unwrap();
+                let n = op["n"].as_u64().unwrap() as usize;
                 for _ in 0..n {
                     self.push_opt_line(old_iter.next().unwrap_or_default());
                 }
+                if old_ix != start {
+                    push_range(&mut changed, start..self.height());
+                }
+                old_ix += n;
+            } else if op_type == "update" {
+                for line in op["lines"].as_array().unwrap() {
+                    let old = old_iter.next().unwrap_or_default();
+                    self.push_opt_line(old.map(|old| old.updated(line)));
+                    old_ix += 1;
+                }
+                push_range(&mut changed, start..self.height());
             } else if op_type == "skip" {
-                let n = op["n"].as_u64().unwrap();
+                let n = op["n"].as_u64().unwrap() as usize;
                 for _ in 0..n {
                     let _ = old_iter.next();
                 }
+                old_ix += n;
             } else if op_type == "invalidate" {
                 let n = op["n"].as_u64().unwrap();
                 for _ in 0..n {
                     self.push_opt_line(None);
                 }
+                push_range(&mut changed, start..self.height());
+            }
+        }
+        if self.height() < old_height {
+            push_range(&mut changed, self.height()..old_height);
+        }
+        if let Some(arr) = update["annotations"].as_array() {
+            let annotations: Vec<Annotation> = arr.iter()
+                .filter_map(Annotation::from_json)
+                .collect();
+            if annotations != self.annotations {
+                let old_annotations = mem::replace(&mut self.annotations, annotations);
+                changed.extend(old_annotations.iter().flat_map(|a| a.line_ranges()));
+                changed.extend(self.annotations.iter().flat_map(|a| a.line_ranges()));
+            }
+        }
+        changed
+    }
+
+    /// The annotated ranges on a line, in utf-16 code units.
+    pub fn annotations_for_line(&self, ix: usize) -> Vec<(AnnotationType, Range<usize>)> {
+        let text = match self.get_line(ix) {
+            Some(line) => line.text(),
+            None => return Vec::new(),
+        };
+        let mut result = Vec::new();
+        for annotation in &self.annotations {
+            for &(start_line, start_col, end_line, end_col) in &annotation.ranges {
+                if ix < start_line || ix > end_line {
+                    continue;
+                }
+                // Columns from plugins, or from before the line changed,
+                // needn't fall between chars; widen them to whole chars.
+                let start = if ix == start_line {
+                    floor_char_boundary(text, start_col)
+                } else {
+                    0
+                };
+                let end = if ix == end_line {
+                    ceil_char_boundary(text, end_col)
+                } else {
+                    text.len()
+                };
+                if start < end {
+                    let start_utf16 = count_utf16(&text[..start]);
+                    let end_utf16 = start_utf16 + count_utf16(&text[start..end]);
+                    result.push((annotation.ty.clone(), start_utf16..end_utf16));
+                }
             }
         }
+        result
+    }
+
+    /// Whether there are non-empty annotated ranges of type `ty`.
+    pub fn has_annotation(&self, ty: &AnnotationType) -> bool {
+        self.annotations.iter()
+            .filter(|annotation| annotation.ty == *ty)
+            .any(|annotation| annotation.ranges.iter()
+                .any(|&(start_line, start_col, end_line, end_col)| {
+                    (start_line, start_col) != (end_line, end_col)
+                }))
     }
 
     pub fn height(&self) -> usize {
         self.lines.len()
     }
 
+    /// The line containing the primary caret.
+    ///
+    /// The core doesn't single out a primary selection, so the first caret
+    /// in the document is treated as primary. Only lines present in the cache
+    /// are considered.
+    pub fn primary_cursor_line(&self) -> Option<usize> {
+        self.lines.iter().position(|line| match line {
+            Some(line) => !line.cursor.is_empty(),
+            None => false,
+        })
+    }
+
+    /// The position of the primary caret (see `primary_cursor_line`), as
+    /// 0-based line and column, with the column counted in characters.
+    pub fn primary_cursor_position(&self) -> Option<(usize, usize)> {
+        let line_num = self.primary_cursor_line()?;
+        let line = self.get_line(line_num)?;
+        let offset_utf16 = line.cursor[0];
+        let mut utf16_count = 0;
+        let col = line.text.chars()
+            .take_while(|c| {
+                utf16_count += c.len_utf16();
+                utf16_count <= offset_utf16
+            })
+            .count();
+        Some((line_num, col))
+    }
+
+    /// The number of carets on lines present in the cache.
+    pub fn cursor_count(&self) -> usize {
+        self.lines.iter()
+            .map(|line| line.as_ref().map_or(0, |line| line.cursor.len()))
+            .sum()
+    }
+
+    /// The line ending used by the document, judged by the first line in
+    /// the cache that has one.
+    pub fn line_ending(&self) -> &'static str {
+        for line in self.lines.iter().filter_map(Option::as_ref) {
+            if line.text.ends_with("\r\n") {
+                return "CRLF";
+            } else if line.text.ends_with('\n') {
+                return "LF";
+            }
+        }
+        "LF"
+    }
+
+    /// The text of the whole document, if every line is in the cache.
+    pub fn text(&self) -> Option<String> {
+        let mut text = String::new();
+        for line in &self.lines {
+            text.push_str(&line.as_ref()?.text);
+        }
+        Some(text)
+    }
+
     pub fn get_line(&self, ix: usize) -> Option<&Line> {
         if ix < self.lines.len() {
             self.lines[ix].as_ref()
@@ -133,6 +338,38 @@ impl LineCache {
     }
 }
 
+/// Appends a range, merging it with the last one if they touch.
+fn push_range(ranges: &mut Vec<Range<usize>>, range: Range<usize>) {
+    if range.start >= range.end {
+        return;
+    }
+    if let Some(last) = ranges.last_mut() {
+        if last.end == range.start {
+            last.end = range.end;
+            return;
+        }
+    }
+    ranges.push(range);
+}
+
+/// The char boundary in `s` at or before `ix`, clamped to the end of `s`.
+fn floor_char_boundary(s: &str, ix: usize) -> usize {
+    let mut ix = min(ix, s.len());
+    while !s.is_char_boundary(ix) {
+        ix -= 1;
+    }
+    ix
+}
+
+/// The char boundary in `s` at or after `ix`, clamped to the end of `s`.
+fn ceil_char_boundary(s: &str, ix: usize) -> usize {
+    let mut ix = min(ix, s.len());
+    while !s.is_char_boundary(ix) {
+        ix += 1;
+    }
+    ix
+}
+
 /// Counts the number of utf-16 code units in the given string.
 fn count_utf16(s: &str) -> usize {
     let mut utf16_count = 0;
@@ -142,3 +379,157 @@ fn count_utf16(s: &str) -> usize {
     }
     utf16_count
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A cache holding `lines`, with selections over `ranges`.
+    fn cache_with_selections(lines: &[&str], ranges: Value) -> LineCache {
+        let lines: Vec<_> = lines.iter().map(|text| json!({ "text": text })).collect();
+        let mut cache = LineCache::new();
+        cache.apply_update(&json!({
+            "ops": [{ "op": "ins", "n": lines.len(), "lines": lines }],
+            "annotations": [{ "type": "selection", "ranges": ranges }],
+        }));
+        cache
+    }
+
+    fn selections(cache: &LineCache, ix: usize) -> Vec<Range<usize>> {
+        cache.annotations_for_line(ix).into_iter()
+            .map(|(ty, range)| {
+                assert_eq!(ty, AnnotationType::Selection);
+                range
+            })
+            .collect()
+    }
+
+    fn insert(lines: &[&str]) -> Value {
+        let lines: Vec<_> = lines.iter().map(|text| json!({ "text": text })).collect();
+        json!({ "op": "ins", "n": lines.len(), "lines": lines })
+    }
+
+    fn text(cache: &LineCache) -> Vec<Option<&str>> {
+        (0..cache.height()).map(|ix| cache.get_line(ix).map(Line::text)).collect()
+    }
+
+    #[test]
+    fn changed_lines() {
+        let mut cache = LineCache::new();
+        let changed = cache.apply_update(&json!({ "ops": [insert(&["a\n", "b\n", "c"])] }));
+        assert_eq!(changed, vec![0..3]);
+
+        // Nothing moved.
+        let changed = cache.apply_update(&json!({ "ops": [{ "op": "copy", "n": 3 }] }));
+        assert_eq!(changed, vec![]);
+
+        // Replace the middle line.
+        let changed = cache.apply_update(&json!({ "ops": [
+            { "op": "copy", "n": 1 },
+            { "op": "skip", "n": 1 },
+            insert(&["x\n"]),
+            { "op": "copy", "n": 1 },
+        ]}));
+        assert_eq!(changed, vec![1..2]);
+        assert_eq!(text(&cache), vec![Some("a\n"), Some("x\n"), Some("c")]);
+
+        // Insert a line, moving the ones after it down.
+        let changed = cache.apply_update(&json!({ "ops": [
+            { "op": "copy", "n": 1 },
+            insert(&["y\n"]),
+            { "op": "copy", "n": 2 },
+        ]}));
+        assert_eq!(changed, vec![1..4]);
+        assert_eq!(text(&cache), vec![Some("a\n"), Some("y\n"), Some("x\n"), Some("c")]);
+
+        // Delete the first line: everything moves up, and the old last line
+        // is now past the end.
+        let changed = cache.apply_update(&json!({ "ops": [
+            { "op": "skip", "n": 1 },
+            { "op": "copy", "n": 3 },
+        ]}));
+        assert_eq!(changed, vec![0..4]);
+        assert_eq!(text(&cache), vec![Some("y\n"), Some("x\n"), Some("c")]);
+
+        // Delete the last line.
+        let changed = cache.apply_update(&json!({ "ops": [
+            { "op": "copy", "n": 2 },
+            { "op": "skip", "n": 1 },
+        ]}));
+        assert_eq!(changed, vec![2..3]);
+
+        let changed = cache.apply_update(&json!({ "ops": [
+            { "op": "skip", "n": 1 },
+            { "op": "invalidate", "n": 1 },
+            { "op": "copy", "n": 1 },
+        ]}));
+        assert_eq!(changed, vec![0..1]);
+        assert_eq!(text(&cache), vec![None, Some("x\n")]);
+    }
+
+    #[test]
+    fn updated_lines() {
+        let mut cache = LineCache::new();
+        cache.apply_update(&json!({ "ops": [insert(&["ab\n", "cd\n", "ef"])] }));
+        let changed = cache.apply_update(&json!({ "ops": [
+            { "op": "copy", "n": 1 },
+            { "op": "update", "n": 1, "lines": [{ "cursor": [1] }] },
+            { "op": "copy", "n": 1 },
+        ]}));
+        assert_eq!(changed, vec![1..2]);
+        assert_eq!(text(&cache), vec![Some("ab\n"), Some("cd\n"), Some("ef")]);
+        assert_eq!(cache.get_line(1).unwrap().cursor(), &[1]);
+        assert_eq!(cache.get_line(0).unwrap().cursor(), &[] as &[usize]);
+    }
+
+    #[test]
+    fn changed_annotations() {
+        let mut cache = cache_with_selections(&["a\n", "b\n", "c\n", "d"],
+            json!([[1, 0, 1, 1]]));
+        let update = json!({
+            "ops": [{ "op": "copy", "n": 4 }],
+            "annotations": [{ "type": "selection", "ranges": [[3, 0, 3, 1]] }],
+        });
+        let mut changed = cache.apply_update(&update);
+        changed.sort_by_key(|r| r.start);
+        assert_eq!(changed, vec![1..2, 3..4]);
+
+        // The same annotations again.
+        assert_eq!(cache.apply_update(&update), vec![]);
+    }
+
+    #[test]
+    fn multi_line_annotations() {
+        let cache = cache_with_selections(&["abcd\n", "ef\n", "ghi\n", "jk"],
+            json!([[0, 2, 2, 1]]));
+        assert_eq!(selections(&cache, 0), vec![2..5]);
+        assert_eq!(selections(&cache, 1), vec![0..3]);
+        assert_eq!(selections(&cache, 2), vec![0..1]);
+        assert_eq!(selections(&cache, 3), vec![]);
+        assert_eq!(selections(&cache, 4), vec![]);
+    }
+
+    #[test]
+    fn annotations_are_clamped() {
+        let cache = cache_with_selections(&["abc\n", "de"], json!([[0, 1, 0, 100], [1, 7, 1, 9]]));
+        assert_eq!(selections(&cache, 0), vec![1..4]);
+        // Past the end of the line, so empty.
+        assert_eq!(selections(&cache, 1), vec![]);
+    }
+
+    #[test]
+    fn annotations_in_utf16() {
+        // 'é' is 2 bytes and 1 utf-16 unit, '😀' is 4 bytes and 2 units.
+        let cache = cache_with_selections(&["aé😀b\n"], json!([[0, 1, 0, 7], [0, 7, 0, 8]]));
+        assert_eq!(selections(&cache, 0), vec![1..4, 4..5]);
+    }
+
+    #[test]
+    fn annotations_inside_chars() {
+        // Both ends fall inside a char, which is then included whole.
+        let cache = cache_with_selections(&["aé😀b\n"], json!([[0, 2, 0, 4]]));
+        assert_eq!(selections(&cache, 0), vec![1..4]);
+        let cache = cache_with_selections(&["😀"], json!([[0, 1, 0, 2]]));
+        assert_eq!(selections(&cache, 0), vec![0..2]);
+    }
+}
@@ -0,0 +1,118 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Logging to the parent console or a log file.
+//!
+//! The app is a windows subsystem app, so stdout and stderr go nowhere.
+//! When started from a terminal we attach to its console, otherwise log
+//! messages go to `xi-win.log` in the config directory (or the temp
+//! directory if there is none).
+
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{self, LevelFilter, Log, Metadata, Record};
+
+use winapi::um::wincon::{AttachConsole, ATTACH_PARENT_PROCESS};
+
+/// Target of the RPC trace messages, enabled with `--trace-rpc`.
+pub const RPC_TARGET: &str = "rpc";
+
+const LOG_FILE_NAME: &str = "xi-win.log";
+
+struct Logger {
+    level: LevelFilter,
+    trace_rpc: bool,
+    out: Mutex<File>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level || (self.trace_rpc && metadata.target() == RPC_TARGET)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut out = self.out.lock().unwrap();
+        let _ = writeln!(out, "{}.{:03} {:5} [{}] {}", time.as_secs(), time.subsec_millis(),
+            record.level(), record.target(), record.args());
+    }
+
+    fn flush(&self) {
+        let _ = self.out.lock().unwrap().flush();
+    }
+}
+
+/// Installs the logger. Messages above `level` are dropped, except RPC
+/// traces if `trace_rpc` is set.
+///
+/// Returns the log file's path, or `None` if logging to the console.
+pub fn init(level: LevelFilter, trace_rpc: bool, config_dir: Option<&Path>)
+    -> Option<PathBuf>
+{
+    let (out, path) = match open_console() {
+        Some(console) => (console, None),
+        None => {
+            let dir = config_dir.map(Path::to_owned).unwrap_or_else(env::temp_dir);
+            let path = dir.join(LOG_FILE_NAME);
+            match File::create(&path) {
+                Ok(file) => (file, Some(path)),
+                Err(_) => return None,
+            }
+        }
+    };
+    let max_level = if trace_rpc { LevelFilter::Trace } else { level };
+    let logger = Logger {
+        level,
+        trace_rpc,
+        out: Mutex::new(out),
+    };
+    if log::set_boxed_logger(Box::new(logger)).is_ok() {
+        log::set_max_level(max_level);
+    }
+    path
+}
+
+/// Parses a level name as given on the command line.
+pub fn parse_level(s: &str) -> Option<LevelFilter> {
+    match s.to_lowercase().as_str() {
+        "off" => Some(LevelFilter::Off),
+        "error" => Some(LevelFilter::Error),
+        "warn" => Some(LevelFilter::Warn),
+        "info" => Some(LevelFilter::Info),
+        "debug" => Some(LevelFilter::Debug),
+        "trace" => Some(LevelFilter::Trace),
+        _ => None,
+    }
+}
+
+/// The default level when none is given.
+pub fn default_level() -> LevelFilter {
+    LevelFilter::Info
+}
+
+/// Attaches to the console of the process that started us, if any.
+fn open_console() -> Option<File> {
+    if unsafe { AttachConsole(ATTACH_PARENT_PROCESS) } == 0 {
+        return None;
+    }
+    OpenOptions::new().write(true).open("CONOUT$").ok()
+}
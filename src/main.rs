@@ -15,10 +15,7 @@
 //! The main module for the xi editor front end.
 
 
-// NOTE: This disables stdout, so no println :(
-// TODO: If we checked what GetStdHandle returns for stdout and see
-// that it is an invalid handle (either -1 or 0), then we can open up
-// up a file to log stdout and SetStdHandle.
+// NOTE: This disables stdout, so use the `log` macros instead (see logging.rs).
 #![windows_subsystem = "windows"]
 
 extern crate winapi;
@@ -27,53 +24,151 @@ extern crate directwrite;
 
 extern crate serde;
 #[macro_use]
+extern crate log;
+#[macro_use]
 extern crate serde_json;
 
 extern crate xi_core_lib;
 extern crate xi_rpc;
-#[macro_use]
 extern crate druid_win_shell;
 extern crate druid;
 
+mod args;
+mod caret;
+mod clipboard;
+mod commands;
+mod damage;
+mod dialogs;
 mod edit_view;
+mod keymap;
+mod keys;
 mod linecache;
+mod logging;
 mod menus;
+mod notification;
+mod palette;
+mod recent;
+mod recorder;
 mod rpc;
+mod settings;
+mod settings_dialog;
+mod status_bar;
+mod tab_bar;
 mod textline;
+mod theme;
 mod xi_thread;
 
+use std::any::Any;
 use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver};
 use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{self, Read};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle, Thread};
+use std::time::{Duration, Instant, SystemTime};
 
 use serde_json::Value;
 
-use edit_view::EditView;
-use menus::MenuEntries;
-use rpc::{Core, Handler};
-use xi_thread::start_xi_thread;
+use directwrite::TextFormat;
+
+use caret::CaretShape;
+use commands::Registry;
+use dialogs::SaveChoice;
+use edit_view::{ApplySettings, EditView, PaletteChoice, RunCommand, SharedFont};
+use keymap::Keymap;
+use menus::{MenuBar, MenuContext, MenuItem};
+use notification::{Notification, ViewInfo};
+use palette::PaletteItem;
+use recent::RecentFiles;
+use recorder::Recorder;
+use rpc::{Core, Handler, RpcError, INVALID_PARAMS, METHOD_NOT_FOUND};
+use status_bar::{StatusBar, StatusBarCommands, StatusItem, ViewStatus};
+use tab_bar::{TabBar, TabBarCommands, TabEvent};
+use theme::Theme;
+use xi_thread::{start_xi_process, start_xi_thread, XiPeer};
 
 use druid_win_shell::win_main::{self};
 use druid_win_shell::window::{Cursor, IdleHandle, WindowBuilder};
 
-use druid::{UiMain, UiState};
+use druid::{ListenerCtx, UiMain, UiState};
 use druid::Id;
+use druid::widget::Column;
 use druid::{FileDialogOptions, FileDialogType};
 
 use edit_view::EditViewCommands;
 
 type ViewId = String;
 
+const APP_NAME: &str = "xi-editor";
+
+/// How long to wait for the core to open a file before giving up on it.
+const NEW_VIEW_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The core is restarted after a crash at most this many times, so that a
+/// file that crashes it doesn't do so forever.
+const MAX_CORE_RESTARTS: usize = 3;
+
+/// How long to wait for the edit view to hand over unsaved text after a crash.
+const RECOVER_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long to wait for the core to exit when quitting.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How often the keymap file is checked for changes while the window is
+/// active. It's also checked whenever the window is activated.
+const KEYMAP_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
 #[derive(Clone)]
 struct ViewState {
     id: Id,
     filename: Option<String>,
     handle: IdleHandle,
+    status_items: Vec<StatusItem>,
+    info: ViewInfo,
 }
 
 #[derive(Clone)]
 struct AppState {
     focused: Option<ViewId>,
     views: HashMap<ViewId, ViewState>,
+    /// Open views, in tab order.
+    tabs: Vec<ViewId>,
+    theme: Theme,
+    /// Languages announced by the core, in menu order.
+    languages: Vec<String>,
+    menu_bar: Option<MenuBar>,
+    /// Where changes made from the Settings menu are stored.
+    settings_domain: settings::Domain,
+    config_dir: Option<PathBuf>,
+    /// The core executable given on the command line, for restarts.
+    core_path: Option<PathBuf>,
+    /// How many times the core has been restarted after crashing.
+    core_restarts: usize,
+    /// Set when quitting, so the core isn't restarted when it exits.
+    shutting_down: bool,
+    recent: RecentFiles,
+    /// Themes announced by the core, in menu order.
+    themes: Vec<String>,
+    theme_name: Option<String>,
+    /// The caret settings from the View > Caret menu.
+    caret_shape: CaretShape,
+    caret_width: u32,
+    caret_blink: bool,
+    /// What the menu item states were last set from.
+    menu_context: Option<MenuContext>,
+    keymap: Arc<Keymap>,
+    /// When the keymap file was modified as of loading it.
+    keymap_modified: Option<SystemTime>,
+    /// Commands run from the command palette, most recent first.
+    recent_commands: Vec<String>,
+    /// Whether the window is active, as last reported by `WM_ACTIVATE`.
+    window_focus: bool,
+    /// The thread sending timer ticks, which sleeps while the window is
+    /// inactive.
+    ticker: Option<Thread>,
 }
 
 impl AppState {
@@ -81,6 +176,27 @@ impl AppState {
         AppState {
             focused: Default::default(),
             views: HashMap::new(),
+            tabs: Vec::new(),
+            theme: Default::default(),
+            languages: Vec::new(),
+            menu_bar: None,
+            settings_domain: settings::Domain::General,
+            config_dir: None,
+            core_path: None,
+            core_restarts: 0,
+            shutting_down: false,
+            recent: Default::default(),
+            themes: Vec::new(),
+            theme_name: None,
+            caret_shape: CaretShape::Bar,
+            caret_width: 1,
+            caret_blink: true,
+            menu_context: None,
+            keymap: Default::default(),
+            keymap_modified: None,
+            recent_commands: Vec::new(),
+            window_focus: false,
+            ticker: None,
         }
     }
 
@@ -98,13 +214,19 @@ impl AppState {
 struct App {
     core: Arc<Mutex<Core>>,
     state: Arc<Mutex<AppState>>,
+    status_bar: Id,
+    tab_bar: Id,
+    registry: Arc<Registry>,
 }
 
 impl App {
-    fn new(core: Core) -> App {
+    fn new(core: Core, status_bar: Id, tab_bar: Id, registry: Arc<Registry>) -> App {
         App {
             core: Arc::new(Mutex::new(core)),
             state: Arc::new(Mutex::new(AppState::new())),
+            status_bar,
+            tab_bar,
+            registry,
         }
     }
 
@@ -118,6 +240,860 @@ impl App {
 
         UiMain::send_ext(&focused.handle.clone(), focused.id, cmd);
     }
+
+    /// Sends a command to a widget, if there is a window to send it to.
+    fn send_widget_cmd<C: Any + Send>(&self, id: Id, cmd: C) {
+        let state = self.get_state();
+        let view_state = state.focused.as_ref().and_then(|view_id| state.views.get(view_id));
+        if let Some(view_state) = view_state {
+            UiMain::send_ext(&view_state.handle, id, cmd);
+        }
+    }
+
+    fn send_status_cmd(&self, cmd: StatusBarCommands) {
+        self.send_widget_cmd(self.status_bar, cmd);
+    }
+
+    fn send_tab_cmd(&self, cmd: TabBarCommands) {
+        self.send_widget_cmd(self.tab_bar, cmd);
+    }
+
+    /// Sends an edit command to a view.
+    fn send_edit(&self, view_id: &str, method: &str, params: &Value) {
+        self.send_notification("edit", &json!({
+            "method": method,
+            "params": params,
+            "view_id": view_id,
+        }));
+    }
+
+    /// Sends an edit action with no parameters to the focused view, if any.
+    fn send_focused_edit(&self, method: &str) {
+        let focused = self.get_state().focused.clone();
+        if let Some(view_id) = focused {
+            self.send_edit(&view_id, method, &json!([]));
+        }
+    }
+
+    /// The handle of the window, through the focused view.
+    fn focused_handle(&self) -> IdleHandle {
+        self.get_state().get_focused_viewstate().handle.clone()
+    }
+
+    /// Makes `view_id` the focused view, showing it in the edit view.
+    fn show_view(&self, view_id: &str) {
+        let (language, items, font) = {
+            let mut state = self.get_state();
+            let view_state = match state.views.get(view_id) {
+                Some(view_state) => view_state.clone(),
+                None => return,
+            };
+            state.focused = Some(view_id.to_owned());
+            let font = view_state.info.font();
+            (view_state.info.language, view_state.status_items, font)
+        };
+        self.send_view_cmd(EditViewCommands::ViewId(view_id.to_owned()));
+        if let Some((face, size)) = font {
+            self.send_view_cmd(EditViewCommands::Font(face, size));
+        }
+        self.send_status_cmd(StatusBarCommands::Language(language));
+        self.send_status_cmd(StatusBarCommands::Items(items));
+        self.update_tab_bar();
+        self.rebuild_language_menu();
+        self.rebuild_plugin_menu();
+        self.rebuild_settings_menu();
+    }
+
+    fn update_tab_bar(&self) {
+        let (titles, selected) = {
+            let state = self.get_state();
+            let titles = state.tabs.iter()
+                .map(|view_id| {
+                    let view_state = &state.views[view_id];
+                    tab_title(view_state.filename.as_ref(), view_state.info.pristine)
+                })
+                .collect();
+            let selected = state.focused.as_ref()
+                .and_then(|focused| state.tabs.iter().position(|view_id| view_id == focused))
+                .unwrap_or(0);
+            (titles, selected)
+        };
+        self.send_tab_cmd(TabBarCommands::Tabs(titles, selected));
+        self.update_title();
+        self.update_menus();
+    }
+
+    /// Enables, disables and checks the menu items to match the app's state.
+    /// Kept up to date by `update_tab_bar` like the title.
+    fn update_menus(&self) {
+        let mut state = self.get_state();
+        let context = {
+            let focused = state.focused.as_ref().and_then(|view_id| state.views.get(view_id));
+            MenuContext {
+                has_view: focused.is_some(),
+                edited: focused.map_or(false, |view_state| view_state.info.edited),
+                tab_count: state.tabs.len(),
+                caret_shape: state.caret_shape,
+                caret_width: state.caret_width,
+                caret_blink: state.caret_blink,
+            }
+        };
+        if state.menu_context.as_ref() == Some(&context) {
+            return;
+        }
+        match state.menu_bar {
+            Some(ref menu_bar) => {
+                menu_bar.set_states(menus::item_states(&menus::menu_model(), &context));
+            }
+            None => return,
+        }
+        state.menu_context = Some(context);
+    }
+
+    fn set_caret_shape(&self, shape: CaretShape) {
+        self.get_state().caret_shape = shape;
+        self.send_view_cmd(EditViewCommands::CaretShape(shape));
+        self.update_menus();
+    }
+
+    fn set_caret_width(&self, width: u32) {
+        self.get_state().caret_width = width;
+        self.send_view_cmd(EditViewCommands::CaretWidth(width as f32));
+        self.update_menus();
+    }
+
+    fn toggle_caret_blink(&self) {
+        {
+            let mut state = self.get_state();
+            state.caret_blink = !state.caret_blink;
+        }
+        self.send_view_cmd(EditViewCommands::ToggleCaretBlink);
+        self.update_menus();
+    }
+
+    /// Sets the window title from the focused view. Kept up to date by
+    /// `update_tab_bar`, since it changes whenever the tabs do.
+    fn update_title(&self) {
+        let state = self.get_state();
+        let menu_bar = match state.menu_bar {
+            Some(ref menu_bar) => menu_bar,
+            None => return,
+        };
+        let title = match state.focused.as_ref().and_then(|view_id| state.views.get(view_id)) {
+            Some(view_state) => {
+                let others = state.views.values()
+                    .filter(|other| other.filename != view_state.filename)
+                    .filter_map(|other| other.filename.as_ref());
+                let name = window_title(view_state.filename.as_ref(), others);
+                let dirty = if view_state.info.pristine { "" } else { "*" };
+                format!("{}{} - {}", dirty, name, APP_NAME)
+            }
+            None => APP_NAME.to_owned(),
+        };
+        menu_bar.set_title(&title);
+    }
+
+    fn select_tab(&self, index: usize) {
+        let view_id = self.get_state().tabs.get(index).cloned();
+        if let Some(view_id) = view_id {
+            self.show_view(&view_id);
+        }
+    }
+
+    /// Selects the tab `delta` positions away from the current one, wrapping around.
+    fn cycle_tab(&self, delta: isize) {
+        let index = {
+            let state = self.get_state();
+            let len = state.tabs.len() as isize;
+            let current = state.focused.as_ref()
+                .and_then(|focused| state.tabs.iter().position(|view_id| view_id == focused));
+            match current {
+                Some(current) if len > 0 => ((current as isize + delta) % len + len) % len,
+                _ => return,
+            }
+        };
+        self.select_tab(index as usize);
+    }
+
+    /// Starts a new core after the old one went away, and reopens the tabs
+    /// in it. Unsaved changes are kept if the edit view has all their text.
+    fn restart_core(&self, handler: AppDispatcher) {
+        let restarts = {
+            let mut state = self.get_state();
+            if state.shutting_down {
+                return;
+            }
+            state.core_restarts += 1;
+            state.core_restarts
+        };
+        if restarts > MAX_CORE_RESTARTS {
+            error!("xi core stopped, not restarting it again");
+            self.show_message("xi core stopped unexpectedly".to_owned());
+            return;
+        }
+        let (tabs, focused, handle, core_path, config_dir) = {
+            let state = self.get_state();
+            let tabs: Vec<_> = state.tabs.iter()
+                .map(|view_id| (view_id.clone(), state.views[view_id].filename.clone()))
+                .collect();
+            let focused = state.focused.as_ref()
+                .and_then(|focused| state.tabs.iter().position(|view_id| view_id == focused));
+            let handle = match state.views.values().next() {
+                Some(view_state) => view_state.handle.clone(),
+                // No window to show anything in, nothing to reopen.
+                None => return,
+            };
+            (tabs, focused, handle, state.core_path.clone(), state.config_dir.clone())
+        };
+        error!("xi core stopped, restarting it");
+        self.show_message("xi core stopped unexpectedly and was restarted".to_owned());
+
+        let texts = if focused.is_some() {
+            let (sender, receiver) = channel();
+            self.send_view_cmd(EditViewCommands::Recover(sender));
+            receiver.recv_timeout(RECOVER_TIMEOUT).unwrap_or_else(|_| {
+                warn!("edit view didn't hand over unsaved text");
+                HashMap::new()
+            })
+        } else {
+            HashMap::new()
+        };
+
+        let recorder = self.get_core().recorder();
+        let mut messages = Vec::new();
+        let (xi_peer, rx) = start_core(core_path.as_ref().map(PathBuf::as_path), &mut messages);
+        let core = Core::new(xi_peer, rx, handler, recorder);
+        *self.get_core() = core;
+        {
+            let mut state = self.get_state();
+            state.views.clear();
+            state.tabs.clear();
+            state.focused = None;
+        }
+        self.send_notification("client_started", &json!({
+            "config_dir": config_dir,
+        }));
+
+        let count = tabs.len();
+        for (view_id, filename) in tabs {
+            let text = texts.get(&view_id).cloned();
+            let messages = mem::replace(&mut messages, Vec::new());
+            self.req_new_view(filename.as_ref().map(String::as_str), handle.clone(),
+                move |app, view_id| {
+                    if let Some(text) = text {
+                        if filename.is_some() {
+                            app.send_edit(view_id, "select_all", &json!([]));
+                        }
+                        app.send_edit(view_id, "insert", &json!({ "chars": text }));
+                        app.send_edit(view_id, "move_to_beginning_of_document", &json!([]));
+                    }
+                    for message in messages {
+                        app.show_message(message);
+                    }
+                    // Once all are back, go back to the tab that was focused.
+                    if app.get_state().tabs.len() == count {
+                        if let Some(focused) = focused {
+                            app.select_tab(focused);
+                        }
+                    }
+                });
+        }
+    }
+
+    /// Stops the plugins and closes the views, then waits for the core to
+    /// exit. Called once the window is gone.
+    fn shutdown(&self) {
+        let views: Vec<_> = {
+            let mut state = self.get_state();
+            state.shutting_down = true;
+            if let Some(ref ticker) = state.ticker {
+                ticker.unpark();
+            }
+            state.tabs.iter().map(|view_id| {
+                let plugins: Vec<_> = state.views[view_id].info.plugins.iter()
+                    .filter(|plugin| plugin.running)
+                    .map(|plugin| plugin.name.clone())
+                    .collect();
+                (view_id.clone(), plugins)
+            }).collect()
+        };
+        for (view_id, plugins) in views {
+            for plugin in plugins {
+                self.send_notification("plugin", &json!({
+                    "command": "stop",
+                    "view_id": view_id,
+                    "plugin_name": plugin,
+                }));
+            }
+            self.send_notification("close_view", &json!({ "view_id": view_id }));
+        }
+        // Not holding the lock while waiting, the receive thread may need it.
+        let core = self.get_core().clone();
+        if core.shutdown(SHUTDOWN_TIMEOUT) {
+            info!("xi core exited");
+        } else {
+            warn!("xi core didn't exit within {:?}", SHUTDOWN_TIMEOUT);
+        }
+    }
+
+    /// Asks for a file and opens it in a new view.
+    fn open_file(&self, ctx: &mut ListenerCtx) {
+        let filename = ctx.file_dialog(FileDialogType::Open, FileDialogOptions::default());
+        if let Some(filename) = extract_string_from_file_dialog(filename) {
+            self.req_new_view(Some(&filename), self.focused_handle(), |_, _| ());
+        }
+    }
+
+    /// Saves the focused view under a new file name.
+    fn save_as(&self, ctx: &mut ListenerCtx) {
+        let view_id = match self.get_state().focused.clone() {
+            Some(view_id) => view_id,
+            None => return,
+        };
+        let filename = ctx.file_dialog(FileDialogType::Save, FileDialogOptions::default());
+        let filename = match extract_string_from_file_dialog(filename) {
+            Some(filename) => filename,
+            None => return,
+        };
+        self.send_notification("save", &json!({
+            "view_id": view_id,
+            "file_path": filename,
+        }));
+        self.add_recent(&filename);
+        if let Some(view_state) = self.get_state().views.get_mut(&view_id) {
+            view_state.filename = Some(filename);
+        }
+        self.update_tab_bar();
+    }
+
+    /// Saves a view, asking for a file name if it doesn't have one yet.
+    /// Returns false if that was cancelled.
+    fn save_view(&self, view_id: &str, ctx: &mut ListenerCtx) -> bool {
+        let filename = self.get_state().views.get(view_id)
+            .and_then(|view_state| view_state.filename.clone());
+        let filename = match filename {
+            Some(filename) => filename,
+            None => {
+                let filename = ctx.file_dialog(FileDialogType::Save, FileDialogOptions::default());
+                match extract_string_from_file_dialog(filename) {
+                    Some(filename) => {
+                        self.add_recent(&filename);
+                        filename
+                    }
+                    None => return false,
+                }
+            }
+        };
+        self.send_notification("save", &json!({
+            "view_id": view_id,
+            "file_path": filename,
+        }));
+        if let Some(view_state) = self.get_state().views.get_mut(view_id) {
+            view_state.filename = Some(filename);
+        }
+        self.update_tab_bar();
+        true
+    }
+
+    /// Asks whether to save a view's unsaved changes before it's closed,
+    /// saving it if so. Returns false if the user cancelled.
+    fn confirm_close(&self, view_id: &str, ctx: &mut ListenerCtx) -> bool {
+        let (name, hwnd) = {
+            let state = self.get_state();
+            let view_state = match state.views.get(view_id) {
+                Some(view_state) if !view_state.info.pristine => view_state,
+                _ => return true,
+            };
+            let hwnd = match state.menu_bar {
+                Some(ref menu_bar) => menu_bar.hwnd(),
+                None => return true,
+            };
+            (tab_title(view_state.filename.as_ref(), true), hwnd)
+        };
+        if self.get_state().focused.as_ref().map(String::as_str) != Some(view_id) {
+            self.show_view(view_id);
+        }
+        match dialogs::ask_save(hwnd, &name) {
+            SaveChoice::Save => self.save_view(view_id, ctx),
+            SaveChoice::DontSave => true,
+            SaveChoice::Cancel => false,
+        }
+    }
+
+    /// Asks about the unsaved changes of every tab. Returns false if the
+    /// user cancelled.
+    fn confirm_close_all(&self, ctx: &mut ListenerCtx) -> bool {
+        let tabs = self.get_state().tabs.clone();
+        tabs.iter().all(|view_id| self.confirm_close(view_id, ctx))
+    }
+
+    /// Closes the tab at `index`, opening an empty one if it was the last.
+    /// Asks first if it has unsaved changes.
+    fn close_tab(&self, index: usize, ctx: &mut ListenerCtx) {
+        let (view_id, handle) = {
+            let state = self.get_state();
+            match state.tabs.get(index) {
+                Some(view_id) => (view_id.clone(), state.views[view_id].handle.clone()),
+                None => return,
+            }
+        };
+        if !self.confirm_close(&view_id, ctx) {
+            return;
+        }
+        self.send_view_cmd(EditViewCommands::Close(view_id.clone()));
+        self.send_notification("close_view", &json!({ "view_id": view_id }));
+        let next = {
+            let mut state = self.get_state();
+            state.tabs.remove(index);
+            state.views.remove(&view_id);
+            let was_focused = state.focused.as_ref() == Some(&view_id);
+            if was_focused {
+                state.focused = None;
+            }
+            if state.tabs.is_empty() {
+                None
+            } else if was_focused {
+                Some(state.tabs[index.min(state.tabs.len() - 1)].clone())
+            } else {
+                state.focused.clone()
+            }
+        };
+        match next {
+            Some(next) => self.show_view(&next),
+            None => self.req_new_view(None, handle, |_, _| ()),
+        }
+    }
+
+    fn close_focused_tab(&self, ctx: &mut ListenerCtx) {
+        let index = {
+            let state = self.get_state();
+            state.focused.as_ref()
+                .and_then(|focused| state.tabs.iter().position(|view_id| view_id == focused))
+        };
+        if let Some(index) = index {
+            self.close_tab(index, ctx);
+        }
+    }
+
+    fn set_menu_bar(&self, menu_bar: MenuBar) {
+        self.get_state().menu_bar = Some(menu_bar);
+        self.update_menus();
+    }
+
+    /// Rebuilds the Theme menu, checking the current theme.
+    fn rebuild_theme_menu(&self) {
+        let state = self.get_state();
+        let menu_bar = match state.menu_bar {
+            Some(ref menu_bar) => menu_bar,
+            None => return,
+        };
+        let items = state.themes.iter().enumerate()
+            .map(|(i, theme)| MenuItem {
+                id: menus::THEME_BASE + i as u32,
+                text: theme.clone(),
+                checked: state.theme_name.as_ref() == Some(theme),
+                enabled: true,
+            })
+            .collect();
+        menu_bar.set_items(menus::THEME_MENU, 0, items);
+    }
+
+    /// Asks the core to switch to the `index`th theme.
+    fn set_theme(&self, index: usize) {
+        let theme = self.get_state().themes.get(index).cloned();
+        if let Some(theme) = theme {
+            self.send_notification("set_theme", &json!({ "theme_name": theme }));
+        }
+    }
+
+    /// Rebuilds the Language menu, checking the focused view's language.
+    fn rebuild_language_menu(&self) {
+        let state = self.get_state();
+        let menu_bar = match state.menu_bar {
+            Some(ref menu_bar) => menu_bar,
+            None => return,
+        };
+        let current = state.focused.as_ref()
+            .and_then(|view_id| state.views.get(view_id))
+            .and_then(|view_state| view_state.info.language.as_ref());
+        let items = state.languages.iter().enumerate()
+            .map(|(i, language)| MenuItem {
+                id: menus::LANGUAGE_BASE + i as u32,
+                text: language.clone(),
+                checked: current == Some(language),
+                enabled: true,
+            })
+            .collect();
+        menu_bar.set_items(menus::LANGUAGE_MENU, 0, items);
+    }
+
+    /// Sets the language of the focused view to the `index`th language.
+    fn set_language(&self, index: usize) {
+        let (view_id, language) = {
+            let state = self.get_state();
+            (state.focused.clone(), state.languages.get(index).cloned())
+        };
+        if let (Some(view_id), Some(language)) = (view_id, language) {
+            self.send_notification("set_language", &json!({
+                "view_id": view_id,
+                "language_id": language,
+            }));
+        }
+    }
+
+    /// Shows a message in the status bar.
+    fn show_message(&self, message: String) {
+        self.send_status_cmd(StatusBarCommands::Message(Some(message)));
+    }
+
+    /// Rebuilds the plugin list in the Plugins menu, checking the plugins
+    /// running in the focused view.
+    fn rebuild_plugin_menu(&self) {
+        let state = self.get_state();
+        let menu_bar = match state.menu_bar {
+            Some(ref menu_bar) => menu_bar,
+            None => return,
+        };
+        let items = state.focused.as_ref()
+            .and_then(|view_id| state.views.get(view_id))
+            .map(|view_state| view_state.info.plugins.iter().enumerate()
+                .map(|(i, plugin)| MenuItem {
+                    id: menus::PLUGIN_BASE + i as u32,
+                    text: plugin.name.clone(),
+                    checked: plugin.running,
+                    enabled: true,
+                })
+                .collect())
+            .unwrap_or_default();
+        menu_bar.set_items(menus::PLUGIN_MENU, 0, items);
+    }
+
+    /// Starts the `index`th plugin in the focused view, or stops it if it's running.
+    fn toggle_plugin(&self, index: usize) {
+        let (view_id, plugin) = {
+            let state = self.get_state();
+            let plugin = state.focused.as_ref()
+                .and_then(|view_id| state.views.get(view_id))
+                .and_then(|view_state| view_state.info.plugins.get(index))
+                .cloned();
+            (state.focused.clone(), plugin)
+        };
+        if let (Some(view_id), Some(plugin)) = (view_id, plugin) {
+            self.send_notification("plugin", &json!({
+                "command": if plugin.running { "stop" } else { "start" },
+                "view_id": view_id,
+                "plugin_name": plugin.name,
+            }));
+        }
+    }
+
+    /// Sends a `plugin_rpc` notification to a plugin of the focused view,
+    /// for debugging.
+    ///
+    /// The clipboard should hold `{"receiver": plugin_name, "method": ..., "params": ...}`.
+    fn send_plugin_rpc_from_clipboard(&self) {
+        let view_id = match self.get_state().focused.clone() {
+            Some(view_id) => view_id,
+            None => return,
+        };
+        let msg = clipboard::get_text()
+            .and_then(|text| serde_json::from_str::<Value>(&text).ok())
+            .filter(|msg| msg["receiver"].is_string() && msg["method"].is_string());
+        match msg {
+            Some(mut msg) => {
+                if msg["params"].is_null() {
+                    msg["params"] = json!({});
+                }
+                self.send_notification("plugin", &json!({
+                    "command": "plugin_rpc",
+                    "view_id": view_id,
+                    "receiver": msg["receiver"],
+                    "rpc": {
+                        "rpc_type": "notification",
+                        "method": msg["method"],
+                        "params": msg["params"],
+                    },
+                }));
+            }
+            None => self.show_message(
+                "clipboard should contain {\"receiver\", \"method\", \"params\"}".to_owned()),
+        }
+    }
+
+    /// Sets the config directory, and loads the recent files kept there.
+    fn set_config_dir(&self, config_dir: Option<PathBuf>) {
+        {
+            let mut state = self.get_state();
+            state.recent = RecentFiles::load(config_dir.as_ref().map(PathBuf::as_path));
+            state.config_dir = config_dir;
+        }
+        self.rebuild_recent_menu();
+    }
+
+    fn rebuild_recent_menu(&self) {
+        let state = self.get_state();
+        if let Some(ref menu_bar) = state.menu_bar {
+            menu_bar.set_items(menus::RECENT_MENU, 0, state.recent.menu_items());
+        }
+    }
+
+    /// Puts `filename` at the top of the recent files.
+    fn add_recent(&self, filename: &str) {
+        self.get_state().recent.add(filename);
+        self.rebuild_recent_menu();
+    }
+
+    fn clear_recent(&self) {
+        self.get_state().recent.clear();
+        self.rebuild_recent_menu();
+    }
+
+    /// Opens the `index`th recent file. If it's gone, drops it from the
+    /// list, along with any others that are.
+    fn open_recent(&self, index: usize, handle: IdleHandle) {
+        let filename = match self.get_state().recent.files().get(index) {
+            Some(filename) => filename.clone(),
+            None => return,
+        };
+        if Path::new(&filename).is_file() {
+            self.req_new_view(Some(&filename), handle, |_, _| ());
+        } else {
+            self.get_state().recent.prune();
+            self.rebuild_recent_menu();
+            self.show_message(format!("{} no longer exists", filename));
+        }
+    }
+
+    /// Makes `keymap` the one in use, showing its keys in the menus.
+    /// `modified` is when its file was last modified.
+    fn set_keymap(&self, keymap: Arc<Keymap>, modified: Option<SystemTime>) {
+        let mut state = self.get_state();
+        if let Some(ref menu_bar) = state.menu_bar {
+            menu_bar.set_texts(menus::item_texts(&self.registry, &keymap));
+        }
+        state.keymap = keymap;
+        state.keymap_modified = modified;
+    }
+
+    /// Called when the window is activated or deactivated. Tells the edit
+    /// view, and wakes the ticker so it starts or stops ticking.
+    fn set_window_focus(&self, has_focus: bool) {
+        let ticker = {
+            let mut state = self.get_state();
+            state.window_focus = has_focus;
+            state.ticker.clone()
+        };
+        if self.get_state().focused.is_some() {
+            self.send_view_cmd(EditViewCommands::Focus(has_focus));
+        }
+        if has_focus {
+            // The keymap may have been edited in another program meanwhile.
+            self.check_keymap();
+        }
+        if let Some(ticker) = ticker {
+            ticker.unpark();
+        }
+    }
+
+    /// Reloads the keymap if its file changed since it was loaded, and
+    /// reports any problems with it.
+    fn check_keymap(&self) {
+        let (path, loaded) = {
+            let state = self.get_state();
+            match state.config_dir {
+                Some(ref dir) => (dir.join(keymap::KEYMAP_FILE), state.keymap_modified),
+                None => return,
+            }
+        };
+        let modified = keymap::modified(&path);
+        if modified == loaded {
+            return;
+        }
+        info!("reloading {:?}", path);
+        let (keymap, problems) = Keymap::load(Some(&path), &self.registry);
+        let keymap = Arc::new(keymap);
+        self.set_keymap(keymap.clone(), modified);
+        if self.get_state().focused.is_some() {
+            self.send_view_cmd(EditViewCommands::Keymap(keymap));
+        }
+        for problem in &problems {
+            warn!("{}", problem);
+        }
+        match problems.len() {
+            0 => (),
+            1 => self.show_message(problems[0].clone()),
+            n => self.show_message(format!("{} (and {} more problems, see the log)",
+                problems[0], n - 1)),
+        }
+    }
+
+    /// Shows the command palette, with the recently used commands first.
+    fn show_palette(&self) {
+        let items = {
+            let state = self.get_state();
+            if state.focused.is_none() {
+                return;
+            }
+            self.registry.commands().iter()
+                .map(|command| PaletteItem {
+                    name: command.name.to_owned(),
+                    title: command.palette_title(),
+                    keys: state.keymap.keys_for(command.name).map(keymap::format_keys),
+                    recent: state.recent_commands.iter().position(|name| name == command.name),
+                })
+                .collect()
+        };
+        self.send_view_cmd(EditViewCommands::ShowPalette(items));
+    }
+
+    /// Asks the focused view for a line to go to.
+    fn show_go_to_line(&self) {
+        if self.get_state().focused.is_some() {
+            self.send_view_cmd(EditViewCommands::GoToLine);
+        }
+    }
+
+    /// Runs a command chosen in the command palette.
+    fn run_from_palette(&self, name: &str, ctx: &mut ListenerCtx) {
+        {
+            let mut state = self.get_state();
+            state.recent_commands.retain(|recent| recent != name);
+            state.recent_commands.insert(0, name.to_owned());
+            state.recent_commands.truncate(palette::MAX_RECENT_COMMANDS);
+        }
+        match self.registry.find(name) {
+            Some(command) => command.run(self, ctx),
+            None => warn!("unknown command {}", name),
+        }
+    }
+
+    fn set_core_path(&self, core_path: Option<PathBuf>) {
+        self.get_state().core_path = core_path;
+    }
+
+    /// Opens the user's preferences file in a new view.
+    fn open_preferences(&self, handle: IdleHandle) {
+        let path = self.get_state().config_dir.as_ref()
+            .map(|dir| settings::preferences_path(dir));
+        match path.as_ref().and_then(|path| path.to_str()) {
+            Some(path) => self.req_new_view(Some(path), handle, |_, _| ()),
+            None => self.show_message("no config directory".to_owned()),
+        }
+    }
+
+    /// Rebuilds the Settings menu from the focused view's config.
+    fn rebuild_settings_menu(&self) {
+        let state = self.get_state();
+        let menu_bar = match state.menu_bar {
+            Some(ref menu_bar) => menu_bar,
+            None => return,
+        };
+        let view_state = state.focused.as_ref().and_then(|view_id| state.views.get(view_id));
+        let null = Value::Null;
+        let config = view_state.map_or(&null, |view_state| &view_state.info.config);
+        let language = view_state.and_then(|view_state| view_state.info.language.as_ref());
+        let items = settings::menu_items(config, state.settings_domain,
+            language.map(String::as_str));
+        menu_bar.set_items(menus::SETTINGS_MENU, 0, items);
+    }
+
+    fn set_settings_domain(&self, domain: settings::Domain) {
+        self.get_state().settings_domain = domain;
+        self.rebuild_settings_menu();
+    }
+
+    /// Shows the settings dialog for the focused view.
+    fn show_settings_dialog(&self) {
+        let cmd = {
+            let state = self.get_state();
+            let view_state = match state.focused.as_ref().and_then(|id| state.views.get(id)) {
+                Some(view_state) => view_state,
+                None => return,
+            };
+            let title = format!("Settings for {}", tab_title(view_state.filename.as_ref(), true));
+            EditViewCommands::ShowSettings(title, view_state.info.config.clone(),
+                state.settings_domain, view_state.info.language.clone())
+        };
+        self.send_view_cmd(cmd);
+    }
+
+    /// Stores the settings changed in the settings dialog in `domain`, which
+    /// becomes the one Settings menu changes go to as well.
+    ///
+    /// The core answers with `config_changed` for the affected views.
+    fn apply_settings(&self, domain: settings::Domain, changes: &Value) {
+        if changes.as_object().map_or(true, |changes| changes.is_empty()) {
+            return;
+        }
+        let domain_json = {
+            let state = self.get_state();
+            state.focused.as_ref()
+                .and_then(|view_id| state.views.get(view_id).map(|v| (view_id, v)))
+                .map(|(view_id, view_state)| {
+                    let language = view_state.info.language.as_ref().map(String::as_str);
+                    domain.to_json(view_id, language)
+                })
+        };
+        match domain_json {
+            Some(Some(domain_json)) => {
+                self.send_notification("modify_user_config", &json!({
+                    "domain": domain_json,
+                    "changes": changes,
+                }));
+                self.set_settings_domain(domain);
+            }
+            Some(None) => self.show_message("the current view has no language".to_owned()),
+            None => (),
+        }
+    }
+
+    /// Sends the config change for a Settings menu command to the core.
+    ///
+    /// The core answers with `config_changed` for the affected views.
+    fn modify_config(&self, cmd: u32) {
+        let request = {
+            let state = self.get_state();
+            state.focused.as_ref()
+                .and_then(|view_id| state.views.get(view_id).map(|v| (view_id, v)))
+                .and_then(|(view_id, view_state)| {
+                    let language = view_state.info.language.as_ref().map(String::as_str);
+                    let domain = state.settings_domain.to_json(view_id, language);
+                    let changes = settings::changes_for_cmd(cmd, &view_state.info.config);
+                    Some((domain, changes?))
+                })
+        };
+        match request {
+            Some((Some(domain), changes)) => {
+                self.send_notification("modify_user_config", &json!({
+                    "domain": domain,
+                    "changes": changes,
+                }));
+            }
+            Some((None, _)) => self.show_message("the current view has no language".to_owned()),
+            None => (),
+        }
+    }
+
+    /// Applies `f` to the status items of the view, and refreshes the
+    /// status bar if that view is focused.
+    fn update_status_items<F>(&self, view_id: &str, f: F)
+        where F: FnOnce(&mut Vec<StatusItem>)
+    {
+        let items = {
+            let mut state = self.get_state();
+            let is_focused = state.focused.as_ref().map_or(false, |id| id.as_str() == view_id);
+            match state.views.get_mut(view_id) {
+                Some(view_state) => {
+                    f(&mut view_state.status_items);
+                    if is_focused { Some(view_state.status_items.clone()) } else { None }
+                }
+                None => None,
+            }
+        };
+        if let Some(items) = items {
+            self.send_status_cmd(StatusBarCommands::Items(items));
+        }
+    }
 }
 
 impl App {
@@ -131,7 +1107,11 @@ impl App {
 }
 
 impl App {
-    fn req_new_view(&self, filename: Option<&str>, handle: IdleHandle) {
+    /// Opens `filename`, or an empty buffer, in a new tab. `then` is called
+    /// with the new view's id once the core has created it.
+    fn req_new_view<F>(&self, filename: Option<&str>, handle: IdleHandle, then: F)
+        where F: FnOnce(&App, &str) + Send + 'static
+    {
         let mut params = json!({});
 
         let filename = if filename.is_some() {
@@ -141,38 +1121,198 @@ impl App {
             None
         };
 
-        let edit_view = 0;
+        let app = self.clone();
         let core = Arc::downgrade(&self.core);
-        let state = self.state.clone();
-        self.core.lock().unwrap().send_request("new_view", &params,
-            move |value| {
-                let view_id = value.clone().as_str().unwrap().to_string();
-                let mut state = state.lock().unwrap();
-                let handle = handle.clone();
-                state.focused = Some(view_id.clone());
-                state.views.insert(view_id.clone(),
-                    ViewState {
-                        id: 0,
-                        filename: filename.clone(),
-                        handle: handle.clone(),
-                    }
-                );
-                UiMain::send_ext(&handle, edit_view, EditViewCommands::Core(core));
-                UiMain::send_ext(&handle, edit_view, EditViewCommands::ViewId(view_id));
+        self.core.lock().unwrap().send_request_with_timeout("new_view", &params,
+            NEW_VIEW_TIMEOUT, move |result| {
+                if let Some(view_id) = app.add_view(filename, handle, result) {
+                    then(&app, &view_id);
+                }
+            },
+            // We've given up on the view by then, so the core mustn't keep it.
+            move |result| {
+                let view_id = match result {
+                    Ok(Value::String(view_id)) => view_id,
+                    _ => return,
+                };
+                if let Some(core) = core.upgrade() {
+                    core.lock().unwrap()
+                        .send_notification("close_view", &json!({ "view_id": view_id }));
+                }
             }
         );
     }
 
+    /// Adds the view the core created in answer to a `new_view` request
+    /// for `filename`, and shows it. Returns its id, or None after showing
+    /// why the request failed.
+    fn add_view(&self, filename: Option<String>, handle: IdleHandle,
+        result: Result<Value, RpcError>) -> Option<ViewId>
+    {
+        let edit_view = 0;
+        let tab_bar = self.tab_bar;
+        let status_bar = self.status_bar;
+        let core = Arc::downgrade(&self.core);
+        let view_id = match result.map(|value| value.as_str().map(str::to_owned)) {
+            Ok(Some(view_id)) => view_id,
+            Ok(None) => {
+                error!("new_view returned something other than a view id");
+                return None;
+            }
+            Err(e) => {
+                let message = match filename {
+                    Some(ref filename) => format!("could not open {}: {}", filename, e),
+                    None => format!("could not create a view: {}", e),
+                };
+                error!("{}", message);
+                UiMain::send_ext(&handle, status_bar, StatusBarCommands::Message(Some(message)));
+                // There must always be a view, for the menu commands.
+                if filename.is_some() && self.get_state().tabs.is_empty() {
+                    self.req_new_view(None, handle, |_, _| ());
+                }
+                return None;
+            }
+        };
+        let theme = {
+            let mut state = self.get_state();
+            state.views.insert(view_id.clone(),
+                ViewState {
+                    id: 0,
+                    filename: filename.clone(),
+                    handle: handle.clone(),
+                    status_items: Vec::new(),
+                    info: ViewInfo::default(),
+                }
+            );
+            state.tabs.push(view_id.clone());
+            state.theme.clone()
+        };
+        if let Some(ref filename) = filename {
+            self.add_recent(filename);
+        }
+        UiMain::send_ext(&handle, edit_view, EditViewCommands::Core(core));
+        UiMain::send_ext(&handle, edit_view, EditViewCommands::Theme(theme.clone()));
+        UiMain::send_ext(&handle, tab_bar, TabBarCommands::Theme(theme.clone()));
+        UiMain::send_ext(&handle, status_bar, StatusBarCommands::Theme(theme));
+        self.show_view(&view_id);
+        Some(view_id)
+    }
+
+    /// Puts the caret of a view at a 1-based line and column, counted in
+    /// characters. The edit view does it once the line is loaded, since the
+    /// core wants the column as a utf-8 offset.
+    fn move_caret(&self, view_id: &str, (line, col): (usize, usize)) {
+        let target = {
+            let state = self.get_state();
+            state.views.get(view_id).map(|view_state| (view_state.handle.clone(), view_state.id))
+        };
+        if let Some((handle, id)) = target {
+            UiMain::send_ext(&handle, id,
+                EditViewCommands::GoTo(view_id.to_owned(), line - 1, col - 1));
+        }
+    }
+
     fn handle_cmd(&self, method: &str, params: &Value) {
-        match method {
-            "update" => self.send_view_cmd(EditViewCommands::ApplyUpdate(params["update"].clone())),
-            "scroll_to" => self.send_view_cmd(EditViewCommands::ScrollTo(params["line"].as_u64().unwrap() as usize)),
-            "available_themes" => (), // TODO
-            "available_plugins" => (), // TODO
-            "available_languages" => (), // TODO
-            "config_changed" => (), // TODO
-            "language_changed" => (), // TODO
-            _ => println!("unhandled core->fe method {}", method),
+        let notification = match Notification::parse(method, params) {
+            Some(notification) => notification,
+            None => {
+                debug!("unhandled core->fe method {}", method);
+                return;
+            }
+        };
+        // What the core tells us about a view is kept first, then shown.
+        let (changed, is_focused) = match notification.view_id() {
+            Some(view_id) => {
+                let mut state = self.get_state();
+                let is_focused = state.focused.as_ref().map_or(false, |id| id.as_str() == view_id);
+                let changed = state.views.get_mut(view_id)
+                    .map_or(false, |view_state| view_state.info.apply(&notification));
+                (changed, is_focused)
+            }
+            None => (false, false),
+        };
+        match notification {
+            Notification::Update { view_id, update } => {
+                self.send_view_cmd(EditViewCommands::ApplyUpdate(view_id.to_owned(),
+                    update.clone()));
+                if changed {
+                    self.update_tab_bar();
+                }
+            }
+            Notification::ScrollTo { view_id, line } => {
+                self.send_view_cmd(EditViewCommands::ScrollTo(view_id.to_owned(), line));
+            }
+            Notification::ThemeChanged { name, theme } => {
+                let theme = Theme::from_json(theme);
+                let has_view = {
+                    let mut state = self.get_state();
+                    state.theme = theme.clone();
+                    state.theme_name = name.map(str::to_owned);
+                    state.focused.is_some()
+                };
+                self.rebuild_theme_menu();
+                if has_view {
+                    self.send_view_cmd(EditViewCommands::Theme(theme.clone()));
+                    self.send_tab_cmd(TabBarCommands::Theme(theme.clone()));
+                    self.send_status_cmd(StatusBarCommands::Theme(theme));
+                }
+            }
+            Notification::AvailableThemes(themes) => {
+                self.get_state().themes = themes;
+                self.rebuild_theme_menu();
+            }
+            Notification::AvailablePlugins { .. } | Notification::PluginStarted { .. } => {
+                if is_focused {
+                    self.rebuild_plugin_menu();
+                }
+            }
+            Notification::PluginStopped { plugin, code, .. } => {
+                if is_focused {
+                    self.rebuild_plugin_menu();
+                }
+                if code != 0 {
+                    self.show_message(format!("plugin {} crashed (exit code {})", plugin, code));
+                }
+            }
+            Notification::AvailableLanguages(languages) => {
+                self.get_state().languages = languages;
+                self.rebuild_language_menu();
+            }
+            Notification::ConfigChanged { view_id, .. } => {
+                if is_focused {
+                    let font = self.get_state().views.get(view_id)
+                        .and_then(|view_state| view_state.info.font());
+                    if let Some((face, size)) = font {
+                        self.send_view_cmd(EditViewCommands::Font(face, size));
+                    }
+                    self.rebuild_settings_menu();
+                }
+            }
+            Notification::LanguageChanged { language, .. } => {
+                if is_focused {
+                    self.send_status_cmd(StatusBarCommands::Language(language));
+                    self.rebuild_language_menu();
+                    self.rebuild_settings_menu();
+                }
+            }
+            Notification::AddStatusItem { view_id, item } => {
+                if let Some(item) = StatusItem::from_json(item) {
+                    self.update_status_items(view_id, |items| {
+                        items.retain(|i| i.key != item.key);
+                        items.push(item);
+                    });
+                }
+            }
+            Notification::UpdateStatusItem { view_id, key, value } => {
+                self.update_status_items(view_id, |items| {
+                    for item in items.iter_mut().filter(|i| i.key == key) {
+                        item.value = value.to_owned();
+                    }
+                });
+            }
+            Notification::RemoveStatusItem { view_id, key } => {
+                self.update_status_items(view_id, |items| items.retain(|i| i.key != key));
+            }
         }
     }
 }
@@ -180,12 +1320,17 @@ impl App {
 #[derive(Clone)]
 struct AppDispatcher {
     app: Arc<Mutex<Option<App>>>,
+    /// The edit view's font, for measuring text for the core.
+    font: SharedFont,
+    registry: Arc<Registry>,
 }
 
 impl AppDispatcher {
     fn new() -> AppDispatcher {
         AppDispatcher {
             app: Default::default(),
+            font: Default::default(),
+            registry: Arc::new(Registry::new()),
         }
     }
 
@@ -195,111 +1340,39 @@ impl AppDispatcher {
 
     fn set_menu_listeners(&self, state: &mut UiState) {
         let app = self.app.clone();
-        state.set_command_listener(move |cmd, mut ctx| {            
+        let registry = self.registry.clone();
+        state.set_command_listener(move |cmd, mut ctx| {
+            let app = match app.lock().unwrap().clone() {
+                Some(app) => app,
+                None => return,
+            };
             match cmd {
-                cmd if cmd == MenuEntries::Exit as u32 => {
-                    ctx.close();
-                }
-                cmd if cmd == MenuEntries::Open as u32 => {
-                    if let Some(app) = app.lock().unwrap().as_ref() {
-                        let filename = ctx.file_dialog(FileDialogType::Open, FileDialogOptions::default());
-                        if filename.is_err() {
-                            return;
-                        }
-                        let filename = filename.unwrap().into_string();
-                        if filename.is_err() { // invalid unicode data
-                            return;
-                        }
-                        let filename = filename.unwrap();
-                        let mut state = app.get_state();
-                        let mut view_state = state.get_focused_viewstate();
-                        app.req_new_view(Some(&filename), view_state.handle.clone());
-                        view_state.filename = Some(filename);
-                    }
+                cmd if cmd >= menus::RECENT_BASE
+                    && cmd < menus::RECENT_BASE + menus::DYNAMIC_RANGE =>
+                {
+                    app.open_recent((cmd - menus::RECENT_BASE) as usize, app.focused_handle());
                 }
-                cmd if cmd == MenuEntries::Save as u32 => {
-                    if let Some(app) = app.lock().unwrap().as_ref() {
-                        {
-                            let mut state = app.get_state();
-                            let mut view_state = state.get_focused_viewstate();
-                            if view_state.filename.is_none() {
-                                let filename = ctx.file_dialog(FileDialogType::Save, FileDialogOptions::default());
-                                let filename = extract_string_from_file_dialog(filename);
-                                if filename.is_none() {
-                                    return;
-                                }
-                                view_state.filename = filename;
-                            }
-                        }
-                        let state = app.get_state();
-                        let view_state = &state.views[&state.get_focused()];
-                        app.send_notification("save", &json!({
-                            "view_id": &state.focused,
-                            "file_path": view_state.filename.clone().unwrap(),
-                        }));
-                    }
+                cmd if cmd >= menus::LANGUAGE_BASE
+                    && cmd < menus::LANGUAGE_BASE + menus::DYNAMIC_RANGE =>
+                {
+                    app.set_language((cmd - menus::LANGUAGE_BASE) as usize);
                 }
-                cmd if cmd == MenuEntries::SaveAs as u32 => {
-                    if let Some(app) = app.lock().unwrap().as_ref() {
-                        let filename = ctx.file_dialog(FileDialogType::Save, FileDialogOptions::default());
-                        let filename = extract_string_from_file_dialog(filename);
-                        if filename.is_none() {
-                            return;
-                        }
-                        app.send_notification("save", &json!({
-                            "view_id": app.get_state().focused,
-                            "file_path": filename.clone().unwrap(),
-                        }));
-                        app.get_state().get_focused_viewstate().filename = filename;
-                    }
+                cmd if cmd >= menus::THEME_BASE
+                    && cmd < menus::THEME_BASE + menus::DYNAMIC_RANGE =>
+                {
+                    app.set_theme((cmd - menus::THEME_BASE) as usize);
                 }
-                cmd if cmd == MenuEntries::Undo as u32 => {
-                    if let Some(app) = app.lock().unwrap().as_ref() {
-                        app.send_view_cmd(EditViewCommands::Undo);
-                    }
+                cmd if cmd >= menus::PLUGIN_BASE
+                    && cmd < menus::PLUGIN_BASE + menus::DYNAMIC_RANGE =>
+                {
+                    app.toggle_plugin((cmd - menus::PLUGIN_BASE) as usize);
                 }
-                cmd if cmd == MenuEntries::Redo as u32 => {
-                    if let Some(app) = app.lock().unwrap().as_ref() {
-                        app.send_view_cmd(EditViewCommands::Redo);
-                    }
-                }
-                // TODO: cut, copy, paste (requires pasteboard)
-                cmd if cmd == MenuEntries::UpperCase as u32 => {
-                    if let Some(app) = app.lock().unwrap().as_ref() {
-                        app.send_view_cmd(EditViewCommands::UpperCase);
-                    }
-                }
-                cmd if cmd == MenuEntries::LowerCase as u32 => {
-                    if let Some(app) = app.lock().unwrap().as_ref() {
-                        app.send_view_cmd(EditViewCommands::LowerCase);
-                    }
-                }
-                cmd if cmd == MenuEntries::Transpose as u32 => {
-                    if let Some(app) = app.lock().unwrap().as_ref() {
-                        app.send_view_cmd(EditViewCommands::Transpose);
-                    }
-                }
-                cmd if cmd == MenuEntries::AddCursorAbove as u32 => {
-                    if let Some(app) = app.lock().unwrap().as_ref() {
-                        app.send_view_cmd(EditViewCommands::AddCursorAbove);
-                    }
-                }
-                cmd if cmd == MenuEntries::AddCursorBelow as u32 => {
-                    if let Some(app) = app.lock().unwrap().as_ref() {
-                        app.send_view_cmd(EditViewCommands::AddCursorBelow);
-                    }
-                }
-                cmd if cmd == MenuEntries::SingleSelection as u32 => {
-                    if let Some(app) = app.lock().unwrap().as_ref() {
-                        app.send_view_cmd(EditViewCommands::SingleSelection);
-                    }
-                }
-                cmd if cmd == MenuEntries::SelectAll as u32 => {
-                    if let Some(app) = app.lock().unwrap().as_ref() {
-                        app.send_view_cmd(EditViewCommands::SelectAll);
-                    }
-                }
-                _ => println!("unexpected cmd {}", cmd),
+                menus::WINDOW_ACTIVATED => app.set_window_focus(true),
+                menus::WINDOW_DEACTIVATED => app.set_window_focus(false),
+                cmd => match registry.by_id(cmd) {
+                    Some(command) => command.run(&app, &mut ctx),
+                    None => warn!("unexpected cmd {}", cmd),
+                },
             }
         });
     }
@@ -309,63 +1382,333 @@ impl AppDispatcher {
 
 impl Handler for AppDispatcher {
     fn notification(&self, method: &str, params: &Value) {
-        // NOTE: For debugging, could be replaced by trace logging
-        // println!("core->fe: {} {}", method, params);
         if let Some(ref app) = *self.app.lock().unwrap() {
             app.handle_cmd(method, params);
         }
     }
+
+    fn disconnected(&self) {
+        let app = self.app.lock().unwrap().clone();
+        if let Some(app) = app {
+            app.restart_core(self.clone());
+        }
+    }
+
+    fn request(&self, method: &str, params: &Value) -> Result<Value, RpcError> {
+        match method {
+            "measure_width" => {
+                let (face, size) = self.font.lock().unwrap().clone();
+                // Made here since DirectWrite objects can't be shared with the UI thread.
+                let factory = directwrite::Factory::new().unwrap();
+                let format = TextFormat::create(&factory)
+                    .with_family(&face)
+                    .with_size(size)
+                    .build()
+                    .map_err(|_| RpcError::new(INVALID_PARAMS, "could not create text format"))?;
+                textline::measure_width(&factory, &format, params)
+                    .ok_or_else(|| RpcError::new(INVALID_PARAMS, "bad measure_width params"))
+            }
+            _ => Err(RpcError::new(METHOD_NOT_FOUND, &format!("unknown method {}", method))),
+        }
+    }
+}
+
+/// The title of a tab showing `filename`, marked with a `*` if it has
+/// unsaved changes.
+fn tab_title(filename: Option<&String>, pristine: bool) -> String {
+    let name = filename
+        .and_then(|filename| Path::new(filename).file_name())
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "untitled".to_owned());
+    if pristine { name } else { format!("*{}", name) }
+}
+
+/// The name of `filename` in the window title: its file name, followed by
+/// the directory it's in if one of the `others` has the same file name.
+fn window_title<'a, I>(filename: Option<&String>, others: I) -> String
+    where I: IntoIterator<Item = &'a String>
+{
+    let name = tab_title(filename, true);
+    let path = match filename {
+        Some(filename) => Path::new(filename),
+        None => return name,
+    };
+    // File names aren't case sensitive on Windows.
+    let collides = others.into_iter()
+        .filter_map(|other| Path::new(other).file_name())
+        .any(|other| other.to_string_lossy().to_lowercase() == name.to_lowercase());
+    match path.parent().and_then(Path::file_name) {
+        Some(dir) if collides => format!("{} ({})", name, dir.to_string_lossy()),
+        _ => name,
+    }
 }
 
 fn extract_string_from_file_dialog(result: Result<std::ffi::OsString, druid::Error>) -> Option<String> {
     if result.is_err() {
-        println!("File dialog encountered an error: {:?}", result);
+        error!("File dialog encountered an error: {:?}", result);
         return None
     }
     let result = result.unwrap().into_string();
     if result.is_err() {
-        println!("Invalid utf returned");
+        warn!("Invalid utf returned");
         return None
     }
     Some(result.unwrap())
 }
 
-fn build_app(state: &mut UiState) {
-    // todo: widgets which support tabs and split panes
-    let edit_view = EditView::new().ui(state);
-    state.set_root(edit_view);
+/// How long the ticker sleeps between ticks while the window is active:
+/// half the caret blink time, so a blink phase is never missed, or just
+/// long enough to check the keymap when carets don't blink.
+fn tick_interval(caret_blink: bool) -> Duration {
+    match caret::system_blink_time() {
+        Some(ms) if caret_blink => Duration::from_millis(ms / 2).min(KEYMAP_CHECK_INTERVAL),
+        _ => KEYMAP_CHECK_INTERVAL,
+    }
+}
+
+/// Starts a thread that sends timer ticks to the focused view and checks the
+/// keymap file for changes, until the app shuts down. It sleeps while the
+/// window is inactive, until `App::set_window_focus` wakes it.
+///
+/// druid doesn't have timers yet, so we poll.
+fn start_ticker(app: App) -> JoinHandle<()> {
+    let ticker_app = app.clone();
+    let ticker = thread::spawn(move || {
+        let app = ticker_app;
+        let mut keymap_checked = Instant::now();
+        loop {
+            let (active, caret_blink) = {
+                let state = app.get_state();
+                if state.shutting_down {
+                    break;
+                }
+                (state.window_focus, state.caret_blink)
+            };
+            if !active {
+                thread::park();
+                continue;
+            }
+            thread::park_timeout(tick_interval(caret_blink));
+            if keymap_checked.elapsed() >= KEYMAP_CHECK_INTERVAL {
+                keymap_checked = Instant::now();
+                app.check_keymap();
+            }
+            let target = {
+                let state = app.get_state();
+                if state.shutting_down || !state.window_focus {
+                    continue;
+                }
+                state.focused.as_ref()
+                    .and_then(|view_id| state.views.get(view_id))
+                    .map(|view_state| (view_state.handle.clone(), view_state.id))
+            };
+            if let Some((handle, id)) = target {
+                UiMain::send_ext(&handle, id, EditViewCommands::Tick);
+            }
+        }
+    });
+    app.get_state().ticker = Some(ticker.thread().clone());
+    ticker
+}
+
+/// Builds the widget tree, returning the ids of the status bar and tab bar.
+fn build_app(state: &mut UiState, handler: &AppDispatcher, keymap: Arc<Keymap>) -> (Id, Id) {
+    // todo: split panes
+    let tab_bar = TabBar::new().ui(state);
+    let edit_view = EditView::new(handler.font.clone(), keymap).ui(state);
+    let status_bar = StatusBar::new().ui(state);
+    let mut column = Column::new();
+    column.set_flex(edit_view, 1.0);
+    let root = column.ui(&[tab_bar, edit_view, status_bar], state);
+    state.add_listener(edit_view, move |status: &mut ViewStatus, mut ctx| {
+        ctx.poke(status_bar, &mut StatusBarCommands::View(status.clone()));
+    });
+    let app = handler.app.clone();
+    let registry = handler.registry.clone();
+    state.add_listener(edit_view, move |run: &mut RunCommand, mut ctx| {
+        let app = app.lock().unwrap().clone();
+        match (app, registry.find(&run.0)) {
+            (Some(app), Some(command)) => command.run(&app, &mut ctx),
+            (_, None) => warn!("unknown command {}", run.0),
+            (None, _) => (),
+        }
+    });
+    let app = handler.app.clone();
+    state.add_listener(edit_view, move |choice: &mut PaletteChoice, mut ctx| {
+        let app = app.lock().unwrap().clone();
+        if let Some(app) = app {
+            app.run_from_palette(&choice.0, &mut ctx);
+        }
+    });
+    let app = handler.app.clone();
+    state.add_listener(edit_view, move |settings: &mut ApplySettings, _ctx| {
+        let app = app.lock().unwrap().clone();
+        if let Some(app) = app {
+            app.apply_settings(settings.0, &settings.1);
+        }
+    });
+    let app = handler.app.clone();
+    state.add_listener(tab_bar, move |event: &mut TabEvent, mut ctx| {
+        if let Some(app) = app.lock().unwrap().as_ref() {
+            match *event {
+                TabEvent::Select(index) => app.select_tab(index),
+                TabEvent::Close(index) => app.close_tab(index, &mut ctx),
+            }
+        }
+    });
+    state.set_root(root);
     state.set_focus(Some(edit_view));
+    (status_bar, tab_bar)
+}
+
+/// Opens the documents named on the command line, or an empty one.
+///
+/// `messages` are shown once the first view is up. Stdin is read on its own
+/// thread, and its view opened when it's closed, so the window isn't held
+/// up by it.
+fn open_inputs(app: &App, inputs: Vec<args::Input>, handle: IdleHandle, mut messages: Vec<String>) {
+    // (file name, caret position) of each file
+    let mut files = Vec::new();
+    let mut stdin = false;
+    for input in inputs {
+        match input {
+            args::Input::File { path, position } => files.push((Some(path), position)),
+            args::Input::Stdin => stdin = true,
+        }
+    }
+    if stdin {
+        let stdin_messages = if files.is_empty() {
+            mem::replace(&mut messages, Vec::new())
+        } else {
+            Vec::new()
+        };
+        let app = app.clone();
+        let handle = handle.clone();
+        thread::spawn(move || {
+            let mut text = String::new();
+            if let Err(e) = io::stdin().read_to_string(&mut text) {
+                let message = format!("could not read stdin: {}", e);
+                error!("{}", message);
+                app.show_message(message);
+                return;
+            }
+            // This thread has nothing else to do, so it can just wait for
+            // each step.
+            let request = app.core.lock().unwrap().send_request_async("new_view", &json!({}));
+            let view_id = match app.add_view(None, handle, rpc::block_on(request)) {
+                Some(view_id) => view_id,
+                None => return,
+            };
+            app.send_edit(&view_id, "insert", &json!({ "chars": text }));
+            app.send_edit(&view_id, "move_to_beginning_of_document", &json!([]));
+            for message in stdin_messages {
+                app.show_message(message);
+            }
+        });
+    } else if files.is_empty() {
+        files.push((None, None));
+    }
+    for (filename, position) in files {
+        let messages = mem::replace(&mut messages, Vec::new());
+        app.req_new_view(filename.as_ref().map(String::as_str), handle.clone(),
+            move |app, view_id| {
+                if let Some(position) = position {
+                    app.move_caret(view_id, position);
+                }
+                for message in messages {
+                    app.show_message(message);
+                }
+            });
+    }
+}
+
+/// Starts the core executable at `core_path`, or the built-in core if
+/// there is none or it can't be started.
+fn start_core(core_path: Option<&Path>, messages: &mut Vec<String>) -> (XiPeer, Receiver<Value>) {
+    match core_path {
+        Some(path) => start_xi_process(path).unwrap_or_else(|e| {
+            let message = format!("could not start core {:?}: {}", path, e);
+            error!("{}", message);
+            messages.push(message);
+            start_xi_thread()
+        }),
+        None => start_xi_thread(),
+    }
 }
 
 fn main() {
     druid_win_shell::init();
 
-    let (xi_peer, rx) = start_xi_thread();
+    let mut messages = Vec::new();
+    let args = args::parse(env::args().skip(1)).unwrap_or_else(|e| {
+        messages.push(format!("bad arguments: {}", e));
+        Default::default()
+    });
+    let config_dir = args.config_dir.clone().or_else(settings::config_dir);
+    if let Some(ref dir) = config_dir {
+        if let Err(e) = fs::create_dir_all(dir) {
+            messages.push(format!("could not create config dir {:?}: {}", dir, e));
+        }
+    }
+    let log_level = args.log_level.unwrap_or_else(logging::default_level);
+    let log_path = logging::init(log_level, args.trace_rpc, config_dir.as_ref().map(PathBuf::as_path));
+    info!("xi-win starting, logging to {:?}", log_path);
+    for message in &messages {
+        error!("{}", message);
+    }
+    let (xi_peer, rx) = start_core(args.core_path.as_ref().map(PathBuf::as_path), &mut messages);
 
     let mut runloop = win_main::RunLoop::new();
     let mut builder = WindowBuilder::new();
     let mut state = UiState::new();
 
     let handler = AppDispatcher::new();
+    let keymap_path = config_dir.as_ref().map(|dir| dir.join(keymap::KEYMAP_FILE));
+    let (keymap, problems) = Keymap::load(keymap_path.as_ref().map(PathBuf::as_path),
+        &handler.registry);
+    for problem in &problems {
+        warn!("{}", problem);
+    }
+    messages.extend(problems);
+    let keymap = Arc::new(keymap);
     handler.set_menu_listeners(&mut state);
-    build_app(&mut state);
-    menus::set_accel(&mut runloop);
+    let (status_bar, tab_bar) = build_app(&mut state, &handler, keymap.clone());
 
     builder.set_handler(Box::new(UiMain::new(state)));
-    builder.set_title("xi-editor");
+    builder.set_title(APP_NAME);
     builder.set_cursor(Cursor::IBeam);
-    builder.set_menu(menus::create_menus());
+    builder.set_menu(menus::create_menus(&handler.registry, &keymap));
     let window = builder.build().unwrap();
 
-    let core = Core::new(xi_peer, rx, handler.clone());
-    let app = App::new(core);
+    let recorder = args.record.as_ref().and_then(|path| match Recorder::create(path) {
+        Ok(recorder) => Some(Arc::new(recorder)),
+        Err(e) => {
+            let message = format!("could not create recording {:?}: {}", path, e);
+            error!("{}", message);
+            messages.push(message);
+            None
+        }
+    });
+    let core = Core::new(xi_peer, rx, handler.clone(), recorder);
+    let app = App::new(core, status_bar, tab_bar, handler.registry.clone());
     handler.set_app(&app);
+    app.set_menu_bar(MenuBar::new(window.get_hwnd().unwrap(), window.get_idle_handle().unwrap()));
+    app.set_keymap(keymap, keymap_path.as_ref().and_then(|path| keymap::modified(path)));
+    menus::route_window_messages(window.get_hwnd().unwrap());
+    app.rebuild_settings_menu();
 
-    app.send_notification("client_started", &json!({}));
+    app.send_notification("client_started", &json!({
+        "config_dir": config_dir,
+    }));
+    app.set_config_dir(config_dir);
+    app.set_core_path(args.core_path);
 
     let handle = window.get_idle_handle().unwrap();
-    app.req_new_view(None, handle);
+    open_inputs(&app, args.inputs, handle, messages);
+    let ticker = start_ticker(app.clone());
 
     window.show();
     runloop.run();
+    app.shutdown();
+    let _ = ticker.join();
 }
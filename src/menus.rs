@@ -14,19 +14,62 @@
 
 //! Configuration and runtime for the main window's menus.
 
-use winapi::shared::minwindef::WORD;
+use std::ffi::OsStr;
+use std::mem;
+use std::os::windows::ffi::OsStrExt;
+use std::ptr::null;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use winapi::shared::basetsd::LONG_PTR;
+use winapi::shared::minwindef::{FALSE, LOWORD, LPARAM, LRESULT, UINT, WPARAM};
+use winapi::shared::windef::HWND;
 use winapi::um::winuser::*;
 
 use druid_win_shell::menu::Menu;
-use druid_win_shell::win_main::RunLoop;
+use druid_win_shell::window::IdleHandle;
+
+use caret::CaretShape;
+use commands::Registry;
+use keymap::Keymap;
+
+/// First id of the dynamically created Language menu items.
+pub const LANGUAGE_BASE: u32 = 0x1000;
+/// First id of the dynamically created Plugins menu items.
+pub const PLUGIN_BASE: u32 = 0x2000;
+/// First id of the dynamically created Open Recent menu items.
+pub const RECENT_BASE: u32 = 0x3000;
+/// First id of the dynamically created Theme menu items.
+pub const THEME_BASE: u32 = 0x4000;
+/// Number of ids reserved for each dynamic menu.
+pub const DYNAMIC_RANGE: u32 = 0x1000;
+/// Sent as a command when the window is activated, see
+/// `route_window_messages`.
+pub const WINDOW_ACTIVATED: u32 = 0x5000;
+/// Sent as a command when the window is deactivated.
+pub const WINDOW_DEACTIVATED: u32 = 0x5001;
+
+/// Position of the Language submenu (View > Language) in the menu bar.
+pub const LANGUAGE_MENU: &[i32] = &[3, 1];
+/// Position of the Plugins menu in the menu bar.
+pub const PLUGIN_MENU: &[i32] = &[4];
+/// Position of the Settings submenu (View > Settings) in the menu bar.
+pub const SETTINGS_MENU: &[i32] = &[3, 2];
+/// Position of the Open Recent submenu (File > Open Recent) in the menu bar.
+pub const RECENT_MENU: &[i32] = &[0, 1];
+/// Position of the Theme submenu (View > Theme) in the menu bar.
+pub const THEME_MENU: &[i32] = &[3, 3];
 
 #[repr(u32)]
+#[derive(Clone, Copy)]
 pub enum MenuEntries {
     // File menu entries
     Exit = 0x100,
     Open,
     Save,
     SaveAs,
+    CloseTab,
+    Preferences,
+    ClearRecent,
 
     // Edit menu entries
     Undo,
@@ -37,58 +80,403 @@ pub enum MenuEntries {
     UpperCase,
     LowerCase,
     Transpose,
+    GoToLine,
 
     // Selection menu entries
     SingleSelection,
     AddCursorAbove,
     AddCursorBelow,
     SelectAll,
+
+    // View menu entries
+    CaretBar,
+    CaretBlock,
+    CaretUnderline,
+    CaretBlink,
+    CaretWidth1,
+    CaretWidth2,
+    CaretWidth3,
+    NextTab,
+    PreviousTab,
+    CommandPalette,
+
+    // View > Settings menu entries
+    DomainGeneral,
+    DomainLanguage,
+    DomainView,
+    TabSize2,
+    TabSize4,
+    TabSize8,
+    TranslateTabs,
+    WordWrap,
+    AutoIndent,
+    SettingsDialog,
 }
 
-pub fn create_menus() -> Menu {
-    let mut file_menu = Menu::new();
-    file_menu.add_item(MenuEntries::Open as u32, "&Open…\tCtrl+O");
-    file_menu.add_item(MenuEntries::Save as u32, "&Save\tCtrl+S");
-    file_menu.add_item(MenuEntries::SaveAs as u32, "Save &as…\tCtrl+Shift+S");
-    file_menu.add_item(MenuEntries::Exit as u32, "E&xit");
+/// What the enabled and checked states of the menu items depend on.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MenuContext {
+    pub has_view: bool,
+    /// Whether the focused view has been edited since it was opened. The
+    /// core doesn't report its undo stack, so this stands in for "there may
+    /// be something to undo". There's no such stand-in for redo, so Redo is
+    /// always enabled.
+    pub edited: bool,
+    pub tab_count: usize,
+    pub caret_shape: CaretShape,
+    pub caret_width: u32,
+    pub caret_blink: bool,
+}
+
+type Condition = fn(&MenuContext) -> bool;
+
+/// A node of the menu model. The menu bar is built from the model by
+/// `create_menus`, and `item_states` evaluates its conditions at runtime.
+pub enum MenuNode {
+    /// An item running the command registered for `entry`, which also
+    /// provides its text.
+    Item {
+        entry: MenuEntries,
+        /// Enabled when this holds; always enabled if there's none.
+        enabled: Option<Condition>,
+        /// Checked when this holds; never checked if there's none.
+        checked: Option<Condition>,
+    },
+    Separator,
+    Submenu(&'static str, Vec<MenuNode>),
+    /// A submenu whose items are set at runtime with `MenuBar::set_items`.
+    Dynamic(&'static str),
+}
+
+impl MenuNode {
+    fn enabled_if(mut self, condition: Condition) -> MenuNode {
+        if let MenuNode::Item { ref mut enabled, .. } = self {
+            *enabled = Some(condition);
+        }
+        self
+    }
+
+    fn checked_if(mut self, condition: Condition) -> MenuNode {
+        if let MenuNode::Item { ref mut checked, .. } = self {
+            *checked = Some(condition);
+        }
+        self
+    }
+}
+
+fn item(entry: MenuEntries) -> MenuNode {
+    MenuNode::Item { entry, enabled: None, checked: None }
+}
+
+/// The same as `item`, but only enabled when there is a view.
+fn view_item(entry: MenuEntries) -> MenuNode {
+    item(entry).enabled_if(|c| c.has_view)
+}
+
+/// The menu bar, from left to right. The positions of the dynamic submenus
+/// must match the `*_MENU` constants.
+pub fn menu_model() -> Vec<MenuNode> {
+    use self::MenuEntries::*;
+    use self::MenuNode::{Dynamic, Separator, Submenu};
+    vec![
+        Submenu("&File", vec![
+            item(Open),
+            // Filled in from the recent files list.
+            Dynamic("Open &Recent"),
+            view_item(Save),
+            view_item(SaveAs),
+            view_item(CloseTab),
+            Separator,
+            item(Preferences),
+            Separator,
+            item(Exit),
+        ]),
+        Submenu("&Edit", vec![
+            item(Undo).enabled_if(|c| c.has_view && c.edited),
+            view_item(Redo),
+            Separator,
+            view_item(Cut),
+            view_item(Copy),
+            view_item(Paste),
+            view_item(UpperCase),
+            view_item(LowerCase),
+            view_item(Transpose),
+            Separator,
+            view_item(GoToLine),
+        ]),
+        Submenu("&Selection", vec![
+            view_item(AddCursorAbove),
+            view_item(AddCursorBelow),
+            view_item(SingleSelection),
+            view_item(SelectAll),
+        ]),
+        Submenu("&View", vec![
+            Submenu("&Caret", vec![
+                item(CaretBar).checked_if(|c| c.caret_shape == CaretShape::Bar),
+                item(CaretBlock).checked_if(|c| c.caret_shape == CaretShape::Block),
+                item(CaretUnderline)
+                    .checked_if(|c| c.caret_shape == CaretShape::Underline),
+                Separator,
+                item(CaretBlink).checked_if(|c| c.caret_blink),
+                Separator,
+                item(CaretWidth1).checked_if(|c| c.caret_width == 1),
+                item(CaretWidth2).checked_if(|c| c.caret_width == 2),
+                item(CaretWidth3).checked_if(|c| c.caret_width == 3),
+            ]),
+            // Filled in from `available_languages`.
+            Dynamic("&Language"),
+            // Filled in from the focused view's config.
+            Dynamic("&Settings"),
+            // Filled in from `available_themes`.
+            Dynamic("&Theme"),
+            Separator,
+            item(NextTab).enabled_if(|c| c.tab_count > 1),
+            item(PreviousTab).enabled_if(|c| c.tab_count > 1),
+            Separator,
+            view_item(CommandPalette),
+        ]),
+        // Filled in from `available_plugins`.
+        Dynamic("&Plugins"),
+    ]
+}
+
+pub fn create_menus(registry: &Registry, keymap: &Keymap) -> Menu {
     let mut menubar = Menu::new();
-    menubar.add_dropdown(file_menu, "&File");
-    let mut edit_menu = Menu::new();
-    edit_menu.add_item(MenuEntries::Undo as u32, "&Undo\tCtrl+Z");
-    edit_menu.add_item(MenuEntries::Redo as u32, "&Redo\tCtrl+Y");
-    edit_menu.add_separator();
-    edit_menu.add_item(MenuEntries::Cut as u32, "Cu&t\tCtrl+X");
-    edit_menu.add_item(MenuEntries::Copy as u32, "&Copy\tCtrl+C");
-    edit_menu.add_item(MenuEntries::Paste as u32, "&Paste\tCtrl+V");
-    edit_menu.add_item(MenuEntries::UpperCase as u32, "Upper Case");
-    edit_menu.add_item(MenuEntries::LowerCase as u32, "Lower Case");
-    edit_menu.add_item(MenuEntries::Transpose as u32, "Transpose");
-    menubar.add_dropdown(edit_menu, "&Edit");
-    let mut selection_menu = Menu::new();
-    selection_menu.add_item(MenuEntries::AddCursorAbove as u32, "Add Cursor Above\tCtrl+Alt+Up");
-    selection_menu.add_item(MenuEntries::AddCursorBelow as u32, "Add Cursor Below\tCtrl+Alt+Down");
-    selection_menu.add_item(MenuEntries::SingleSelection as u32, "Single Selection\tEscape");
-    selection_menu.add_item(MenuEntries::SelectAll as u32, "Select All\tCtrl+A");
-    menubar.add_dropdown(selection_menu, "&Selection");
+    for node in &menu_model() {
+        add_node(&mut menubar, node, registry, keymap);
+    }
     menubar
 }
 
-pub fn set_accel(runloop: &mut RunLoop) {
-    let accel = accel!{
-        FCONTROL, 'O', MenuEntries::Open,
-        FCONTROL, 'S', MenuEntries::Save,
-        FCONTROL | FSHIFT, 'S', MenuEntries::SaveAs,
-
-        FCONTROL, 'Z', MenuEntries::Undo,
-        FCONTROL, 'Y', MenuEntries::Redo,
-        FCONTROL | FSHIFT, 'Z', MenuEntries::Redo,
-        FCONTROL, 'X', MenuEntries::Cut,
-        FCONTROL, 'C', MenuEntries::Copy,
-        FCONTROL, 'V', MenuEntries::Paste,
-        FCONTROL, 'T', MenuEntries::Transpose,
-
-        // Note: arrow keys and escape are actually handled in edit_view
-        FCONTROL, 'A', MenuEntries::SelectAll,
-    };
-    runloop.set_accel(&accel);
+fn add_node(menu: &mut Menu, node: &MenuNode, registry: &Registry, keymap: &Keymap) {
+    match *node {
+        MenuNode::Item { entry, .. } => {
+            let text = registry.by_id(entry as u32).map(|command| keymap.menu_text(command));
+            menu.add_item(entry as u32, text.as_ref().map_or("?", String::as_str));
+        }
+        MenuNode::Separator => menu.add_separator(),
+        MenuNode::Submenu(text, ref children) => {
+            let mut submenu = Menu::new();
+            for child in children {
+                add_node(&mut submenu, child, registry, keymap);
+            }
+            menu.add_dropdown(submenu, text);
+        }
+        MenuNode::Dynamic(text) => menu.add_dropdown(Menu::new(), text),
+    }
+}
+
+/// The state of a menu item, as set by `MenuBar::set_states`.
+#[derive(Clone, Debug)]
+pub struct ItemState {
+    pub id: u32,
+    pub enabled: bool,
+    /// None for items that aren't check items.
+    pub checked: Option<bool>,
+}
+
+/// Evaluates the conditions of the items in `model`.
+pub fn item_states(model: &[MenuNode], context: &MenuContext) -> Vec<ItemState> {
+    let mut states = Vec::new();
+    for node in model {
+        match *node {
+            MenuNode::Item { entry, enabled, checked, .. } => states.push(ItemState {
+                id: entry as u32,
+                enabled: enabled.map_or(true, |enabled| enabled(context)),
+                checked: checked.map(|checked| checked(context)),
+            }),
+            MenuNode::Submenu(_, ref children) => states.extend(item_states(children, context)),
+            MenuNode::Separator | MenuNode::Dynamic(_) => (),
+        }
+    }
+    states
+}
+
+/// The ids of the items in `model`.
+pub fn entries(model: &[MenuNode]) -> Vec<u32> {
+    let mut ids = Vec::new();
+    for node in model {
+        match *node {
+            MenuNode::Item { entry, .. } => ids.push(entry as u32),
+            MenuNode::Submenu(_, ref children) => ids.extend(entries(children)),
+            MenuNode::Separator | MenuNode::Dynamic(_) => (),
+        }
+    }
+    ids
+}
+
+/// The text of each item in the menu model, with the keys bound to its
+/// command in `keymap`.
+pub fn item_texts(registry: &Registry, keymap: &Keymap) -> Vec<(u32, String)> {
+    entries(&menu_model()).into_iter()
+        .filter_map(|id| registry.by_id(id).map(|command| (id, keymap.menu_text(command))))
+        .collect()
+}
+
+/// An item of a dynamically built menu.
+#[derive(Clone, Debug)]
+pub struct MenuItem {
+    pub id: u32,
+    pub text: String,
+    pub checked: bool,
+    pub enabled: bool,
+}
+
+impl MenuItem {
+    /// A separator line. Separators are items with no text.
+    pub fn separator() -> MenuItem {
+        MenuItem {
+            id: 0,
+            text: String::new(),
+            checked: false,
+            enabled: true,
+        }
+    }
+}
+
+/// A window handle that can be moved to the UI thread.
+#[derive(Clone, Copy)]
+struct Hwnd(HWND);
+
+unsafe impl Send for Hwnd {}
+
+/// A handle for changing the menu bar after the window is built.
+///
+/// druid only supports static menus, so this uses winapi directly. Changes
+/// are made on the UI thread, so this can be used from any thread.
+#[derive(Clone)]
+pub struct MenuBar {
+    hwnd: Hwnd,
+    idle_handle: IdleHandle,
+}
+
+impl MenuBar {
+    pub fn new(hwnd: HWND, idle_handle: IdleHandle) -> MenuBar {
+        MenuBar {
+            hwnd: Hwnd(hwnd),
+            idle_handle,
+        }
+    }
+
+    /// Enables or disables, and checks or unchecks, items anywhere in the
+    /// menu bar.
+    pub fn set_states(&self, states: Vec<ItemState>) {
+        let hwnd = self.hwnd;
+        self.idle_handle.add_idle(move |_| unsafe {
+            let hmenu = GetMenu(hwnd.0);
+            if hmenu.is_null() {
+                return;
+            }
+            for state in &states {
+                let enable = if state.enabled { MF_ENABLED } else { MF_GRAYED };
+                EnableMenuItem(hmenu, state.id, MF_BYCOMMAND | enable);
+                if let Some(checked) = state.checked {
+                    let check = if checked { MF_CHECKED } else { MF_UNCHECKED };
+                    CheckMenuItem(hmenu, state.id, MF_BYCOMMAND | check);
+                }
+            }
+        });
+    }
+
+    /// Changes the text of items anywhere in the menu bar, by id.
+    pub fn set_texts(&self, texts: Vec<(u32, String)>) {
+        let hwnd = self.hwnd;
+        self.idle_handle.add_idle(move |_| unsafe {
+            let hmenu = GetMenu(hwnd.0);
+            if hmenu.is_null() {
+                return;
+            }
+            for (id, text) in texts {
+                let mut text = to_wide(&text);
+                let mut info: MENUITEMINFOW = mem::zeroed();
+                info.cbSize = mem::size_of::<MENUITEMINFOW>() as UINT;
+                info.fMask = MIIM_STRING;
+                info.dwTypeData = text.as_mut_ptr();
+                SetMenuItemInfoW(hmenu, id, FALSE, &info);
+            }
+        });
+    }
+
+    /// The window the menu bar belongs to.
+    pub fn hwnd(&self) -> HWND {
+        self.hwnd.0
+    }
+
+    /// Sets the title of the window. Like the menu changes, this is done on
+    /// the UI thread.
+    pub fn set_title(&self, title: &str) {
+        let hwnd = self.hwnd;
+        let title = to_wide(title);
+        self.idle_handle.add_idle(move |_| unsafe {
+            SetWindowTextW(hwnd.0, title.as_ptr());
+        });
+    }
+
+    /// Replaces the items from position `start` onward in the submenu at
+    /// `path` (positions of the dropdowns, starting from the menu bar).
+    pub fn set_items(&self, path: &'static [i32], start: i32, items: Vec<MenuItem>) {
+        let hwnd = self.hwnd;
+        self.idle_handle.add_idle(move |_| unsafe {
+            let mut hmenu = GetMenu(hwnd.0);
+            for &ix in path {
+                if hmenu.is_null() {
+                    return;
+                }
+                hmenu = GetSubMenu(hmenu, ix);
+            }
+            if hmenu.is_null() {
+                return;
+            }
+            while GetMenuItemCount(hmenu) > start {
+                DeleteMenu(hmenu, start as u32, MF_BYPOSITION);
+            }
+            for item in &items {
+                if item.text.is_empty() {
+                    AppendMenuW(hmenu, MF_SEPARATOR, 0, null());
+                    continue;
+                }
+                let flags = MF_STRING | if item.checked { MF_CHECKED } else { MF_UNCHECKED }
+                    | if item.enabled { MF_ENABLED } else { MF_GRAYED };
+                AppendMenuW(hmenu, flags, item.id as usize, to_wide(&item.text).as_ptr());
+            }
+            DrawMenuBar(hwnd.0);
+        });
+    }
+}
+
+/// The window procedure that `route_window_messages` replaced.
+static DEFAULT_WNDPROC: AtomicUsize = AtomicUsize::new(0);
+
+/// Routes window messages that druid doesn't handle to the command listener.
+/// The close button (and Alt+F4) send `MenuEntries::Exit` instead of
+/// destroying the window, so quitting always goes through the same path, and
+/// activation sends `WINDOW_ACTIVATED` or `WINDOW_DEACTIVATED`.
+pub fn route_window_messages(hwnd: HWND) {
+    unsafe {
+        let old = SetWindowLongPtrW(hwnd, GWLP_WNDPROC, routing_wndproc as LONG_PTR);
+        DEFAULT_WNDPROC.store(old as usize, Ordering::SeqCst);
+    }
+}
+
+unsafe extern "system" fn routing_wndproc(hwnd: HWND, msg: UINT, wparam: WPARAM,
+    lparam: LPARAM) -> LRESULT
+{
+    if msg == WM_CLOSE {
+        PostMessageW(hwnd, WM_COMMAND, MenuEntries::Exit as WPARAM, 0);
+        return 0;
+    }
+    if msg == WM_ACTIVATE {
+        let cmd = if LOWORD(wparam as u32) == WA_INACTIVE {
+            WINDOW_DEACTIVATED
+        } else {
+            WINDOW_ACTIVATED
+        };
+        PostMessageW(hwnd, WM_COMMAND, cmd as WPARAM, 0);
+    }
+    let default: WNDPROC = mem::transmute(DEFAULT_WNDPROC.load(Ordering::SeqCst));
+    CallWindowProcW(default, hwnd, msg, wparam, lparam)
+}
+
+fn to_wide(s: &str) -> Vec<u16> {
+    OsStr::new(s).encode_wide().chain(Some(0)).collect()
 }
@@ -0,0 +1,218 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Notifications from the core, and what they tell us about each view
+//! apart from how it's shown.
+//!
+//! Nothing here depends on the window, so `xi-replay` uses this module too
+//! and a recording is interpreted the same way as in the app.
+
+use serde_json::Value;
+
+/// A notification from the core, with its params picked apart.
+#[derive(Debug)]
+pub enum Notification<'a> {
+    Update { view_id: &'a str, update: &'a Value },
+    ScrollTo { view_id: &'a str, line: usize },
+    ThemeChanged { name: Option<&'a str>, theme: &'a Value },
+    AvailableThemes(Vec<String>),
+    AvailablePlugins { view_id: &'a str, plugins: Vec<PluginState> },
+    PluginStarted { view_id: &'a str, plugin: &'a str },
+    PluginStopped { view_id: &'a str, plugin: &'a str, code: i64 },
+    AvailableLanguages(Vec<String>),
+    ConfigChanged { view_id: &'a str, changes: &'a Value },
+    LanguageChanged { view_id: &'a str, language: Option<String> },
+    /// The item is left as json, since how it's shown is up to the front end.
+    AddStatusItem { view_id: &'a str, item: &'a Value },
+    UpdateStatusItem { view_id: &'a str, key: &'a str, value: &'a str },
+    RemoveStatusItem { view_id: &'a str, key: &'a str },
+}
+
+impl<'a> Notification<'a> {
+    /// Parses a notification, or returns `None` if the method isn't one we
+    /// handle or a required param is missing.
+    pub fn parse(method: &str, params: &'a Value) -> Option<Notification<'a>> {
+        let view_id = params["view_id"].as_str();
+        let notification = match method {
+            "update" => Notification::Update { view_id: view_id?, update: &params["update"] },
+            "scroll_to" => Notification::ScrollTo {
+                view_id: view_id?,
+                line: params["line"].as_u64()? as usize,
+            },
+            "theme_changed" => Notification::ThemeChanged {
+                name: params["name"].as_str(),
+                theme: &params["theme"],
+            },
+            "available_themes" => Notification::AvailableThemes(strings(&params["themes"])),
+            "available_plugins" => Notification::AvailablePlugins {
+                view_id: view_id?,
+                plugins: params["plugins"].as_array()
+                    .map(|arr| arr.iter()
+                        .filter_map(|p| Some(PluginState {
+                            name: p["name"].as_str()?.to_owned(),
+                            running: p["running"].as_bool().unwrap_or(false),
+                        }))
+                        .collect())
+                    .unwrap_or_default(),
+            },
+            "plugin_started" => Notification::PluginStarted {
+                view_id: view_id?,
+                plugin: params["plugin"].as_str().unwrap_or_default(),
+            },
+            "plugin_stopped" => Notification::PluginStopped {
+                view_id: view_id?,
+                plugin: params["plugin"].as_str().unwrap_or_default(),
+                code: params["code"].as_i64().unwrap_or(0),
+            },
+            "available_languages" => {
+                Notification::AvailableLanguages(strings(&params["languages"]))
+            }
+            "config_changed" => Notification::ConfigChanged {
+                view_id: view_id?,
+                changes: &params["changes"],
+            },
+            "language_changed" => Notification::LanguageChanged {
+                view_id: view_id?,
+                language: params["language_id"].as_str().map(str::to_owned),
+            },
+            "add_status_item" => Notification::AddStatusItem { view_id: view_id?, item: params },
+            "update_status_item" => Notification::UpdateStatusItem {
+                view_id: view_id?,
+                key: params["key"].as_str().unwrap_or_default(),
+                value: params["value"].as_str().unwrap_or_default(),
+            },
+            "remove_status_item" => Notification::RemoveStatusItem {
+                view_id: view_id?,
+                key: params["key"].as_str().unwrap_or_default(),
+            },
+            _ => return None,
+        };
+        Some(notification)
+    }
+
+    /// The view the notification is about, unless it's about all of them.
+    pub fn view_id(&self) -> Option<&'a str> {
+        match *self {
+            Notification::Update { view_id, .. }
+            | Notification::ScrollTo { view_id, .. }
+            | Notification::AvailablePlugins { view_id, .. }
+            | Notification::PluginStarted { view_id, .. }
+            | Notification::PluginStopped { view_id, .. }
+            | Notification::ConfigChanged { view_id, .. }
+            | Notification::LanguageChanged { view_id, .. }
+            | Notification::AddStatusItem { view_id, .. }
+            | Notification::UpdateStatusItem { view_id, .. }
+            | Notification::RemoveStatusItem { view_id, .. } => Some(view_id),
+            Notification::ThemeChanged { .. }
+            | Notification::AvailableThemes(_)
+            | Notification::AvailableLanguages(_) => None,
+        }
+    }
+}
+
+/// The strings in a json array, skipping anything else.
+fn strings(value: &Value) -> Vec<String> {
+    value.as_array()
+        .map(|arr| arr.iter().filter_map(|s| s.as_str()).map(str::to_owned).collect())
+        .unwrap_or_default()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PluginState {
+    pub name: String,
+    pub running: bool,
+}
+
+/// What the core has told us about a view.
+#[derive(Clone, Debug)]
+pub struct ViewInfo {
+    pub language: Option<String>,
+    pub plugins: Vec<PluginState>,
+    /// The view's config, as sent in `config_changed`.
+    pub config: Value,
+    /// False if there are unsaved changes.
+    pub pristine: bool,
+    /// Whether there have been unsaved changes since the view was opened.
+    pub edited: bool,
+}
+
+impl Default for ViewInfo {
+    fn default() -> ViewInfo {
+        ViewInfo {
+            language: None,
+            plugins: Vec::new(),
+            config: json!({}),
+            pristine: true,
+            edited: false,
+        }
+    }
+}
+
+impl ViewInfo {
+    /// Applies a notification about this view, returning whether anything
+    /// changed.
+    pub fn apply(&mut self, notification: &Notification) -> bool {
+        match *notification {
+            Notification::Update { update, .. } => match update["pristine"].as_bool() {
+                Some(pristine) if pristine != self.pristine => {
+                    self.pristine = pristine;
+                    self.edited |= !pristine;
+                    true
+                }
+                _ => false,
+            },
+            Notification::AvailablePlugins { ref plugins, .. } => {
+                self.plugins = plugins.clone();
+                true
+            }
+            Notification::PluginStarted { plugin, .. } => self.set_running(plugin, true),
+            Notification::PluginStopped { plugin, .. } => self.set_running(plugin, false),
+            Notification::ConfigChanged { changes, .. } => {
+                merge_config(&mut self.config, changes);
+                true
+            }
+            Notification::LanguageChanged { ref language, .. } => {
+                let changed = self.language != *language;
+                self.language = language.clone();
+                changed
+            }
+            _ => false,
+        }
+    }
+
+    fn set_running(&mut self, name: &str, running: bool) -> bool {
+        let mut changed = false;
+        for plugin in self.plugins.iter_mut().filter(|p| p.name == name) {
+            changed |= plugin.running != running;
+            plugin.running = running;
+        }
+        changed
+    }
+
+    /// Font face and size from the config, if both are set.
+    pub fn font(&self) -> Option<(String, f32)> {
+        let face = self.config["font_face"].as_str()?;
+        let size = self.config["font_size"].as_f64()?;
+        Some((face.to_owned(), size as f32))
+    }
+}
+
+/// Merges the `changes` of a `config_changed` notification into `config`.
+fn merge_config(config: &mut Value, changes: &Value) {
+    if let (Some(config), Some(changes)) = (config.as_object_mut(), changes.as_object()) {
+        for (key, value) in changes {
+            config.insert(key.clone(), value.clone());
+        }
+    }
+}
@@ -0,0 +1,367 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The command palette, shown over the edit view to find and run commands
+//! by name. It also serves as the go-to-line prompt.
+
+use std::cmp::Ordering;
+
+use winapi::um::winuser::*;
+
+use direct2d::RenderTarget;
+use directwrite;
+use directwrite::{TextFormat, TextLayout};
+
+use druid_win_shell::util::default_text_options;
+use druid_win_shell::window::M_CTRL;
+
+use theme::{solid_brush, Theme};
+
+/// How many commands are remembered as recently used.
+pub const MAX_RECENT_COMMANDS: usize = 20;
+
+/// Number of commands shown at once.
+const ROWS: usize = 12;
+const ROW_HEIGHT: f32 = 22.0;
+const WIDTH: f32 = 560.0;
+const PAD: f32 = 6.0;
+const TOP: f32 = 8.0;
+
+/// A command as offered in the palette.
+#[derive(Clone, Debug)]
+pub struct PaletteItem {
+    pub name: String,
+    pub title: String,
+    /// The keys running the command, if it has any.
+    pub keys: Option<String>,
+    /// The position among the recently used commands, 0 being the last one.
+    pub recent: Option<usize>,
+}
+
+/// What a key pressed in the palette leads to.
+pub enum PaletteKey {
+    Continue,
+    Close,
+    /// Run the named command; the palette closes.
+    Run(String),
+    /// Go to the position typed in the go-to-line prompt.
+    GoTo(String),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Commands,
+    GoToLine,
+}
+
+pub struct Palette {
+    mode: Mode,
+    /// Shown under the query when there's no error.
+    hint: Option<String>,
+    /// Shown under the query until it changes.
+    error: Option<String>,
+    items: Vec<PaletteItem>,
+    query: String,
+    /// The items matching the query, best first.
+    matches: Vec<usize>,
+    selected: usize,
+    /// The first of the matches shown.
+    first: usize,
+}
+
+impl Palette {
+    pub fn new(items: Vec<PaletteItem>) -> Palette {
+        let mut palette = Palette {
+            mode: Mode::Commands,
+            hint: None,
+            error: None,
+            items,
+            query: String::new(),
+            matches: Vec::new(),
+            selected: 0,
+            first: 0,
+        };
+        palette.update_matches();
+        palette
+    }
+
+    /// A prompt for a position to go to, with `hint` shown under it.
+    pub fn go_to_line(hint: String) -> Palette {
+        Palette {
+            mode: Mode::GoToLine,
+            hint: Some(hint),
+            error: None,
+            items: Vec::new(),
+            query: String::new(),
+            matches: Vec::new(),
+            selected: 0,
+            first: 0,
+        }
+    }
+
+    /// Shows what's wrong with the query, until it's changed.
+    pub fn set_error(&mut self, error: String) {
+        self.error = Some(error);
+    }
+
+    /// Adds a typed character to the query.
+    pub fn insert(&mut self, ch: u32) {
+        if let Some(c) = ::std::char::from_u32(ch) {
+            if ch >= 0x20 {
+                self.query.push(c);
+                self.update_matches();
+            }
+        }
+    }
+
+    pub fn keydown(&mut self, vk_code: i32, mods: u32) -> PaletteKey {
+        let selected = self.selected as isize;
+        match vk_code {
+            VK_ESCAPE => return PaletteKey::Close,
+            VK_RETURN if self.mode == Mode::GoToLine => {
+                return PaletteKey::GoTo(self.query.clone());
+            }
+            VK_RETURN => {
+                return match self.matches.get(self.selected) {
+                    Some(&ix) => PaletteKey::Run(self.items[ix].name.clone()),
+                    None => PaletteKey::Close,
+                };
+            }
+            VK_UP => self.select(selected - 1),
+            VK_DOWN => self.select(selected + 1),
+            VK_PRIOR => self.select(selected - ROWS as isize),
+            VK_NEXT => self.select(selected + ROWS as isize),
+            VK_BACK => {
+                if mods == M_CTRL {
+                    self.query.clear();
+                } else {
+                    self.query.pop();
+                }
+                self.update_matches();
+            }
+            _ => (),
+        }
+        PaletteKey::Continue
+    }
+
+    /// Selects the `ix`th match, clamped to the matches, and scrolls it
+    /// into view.
+    fn select(&mut self, ix: isize) {
+        let last = self.matches.len().saturating_sub(1) as isize;
+        self.selected = ix.max(0).min(last) as usize;
+        if self.selected < self.first {
+            self.first = self.selected;
+        } else if self.selected >= self.first + ROWS {
+            self.first = self.selected + 1 - ROWS;
+        }
+    }
+
+    /// Finds the items matching the query, best first. Among equally good
+    /// matches, recently used commands come first, so they lead the list
+    /// while the query is empty.
+    fn update_matches(&mut self) {
+        self.error = None;
+        let matches = {
+            let query = &self.query;
+            let mut scored = self.items.iter().enumerate()
+                .filter_map(|(ix, item)| {
+                    let name = item.name.replace('_', " ");
+                    let title_score = fuzzy_score(query, &item.title);
+                    let score = match (title_score, fuzzy_score(query, &name)) {
+                        (Some(a), Some(b)) => a.max(b),
+                        (a, b) => a.or(b)?,
+                    };
+                    Some((ix, score))
+                })
+                .collect::<Vec<_>>();
+            let items = &self.items;
+            scored.sort_by(|&(a, a_score), &(b, b_score)| {
+                let (a, b) = (&items[a], &items[b]);
+                b_score.cmp(&a_score)
+                    .then(match (a.recent, b.recent) {
+                        (Some(a), Some(b)) => a.cmp(&b),
+                        (Some(_), None) => Ordering::Less,
+                        (None, Some(_)) => Ordering::Greater,
+                        (None, None) => Ordering::Equal,
+                    })
+                    .then_with(|| a.title.cmp(&b.title))
+            });
+            scored.into_iter().map(|(ix, _)| ix).collect()
+        };
+        self.matches = matches;
+        self.first = 0;
+        self.select(0);
+    }
+
+    /// Paints the palette at the top of an area `width` px wide.
+    pub fn paint<R: RenderTarget>(&self, rt: &mut R, factory: &directwrite::Factory,
+        theme: &Theme, width: f32)
+    {
+        let text_format = TextFormat::create(factory)
+            .with_family("Segoe UI")
+            .with_size(12.0)
+            .build()
+            .unwrap();
+        let bg = solid_brush(rt, theme.line_highlight);
+        let input_bg = solid_brush(rt, theme.background);
+        let sel = solid_brush(rt, theme.selection);
+        let fg = solid_brush(rt, theme.foreground);
+        let dim = solid_brush(rt, theme.foreground.with_alpha(0.6));
+
+        let box_width = WIDTH.min(width - 2.0 * PAD);
+        let left = ((width - box_width) / 2.0).round();
+        let right = left + box_width;
+        let message = self.error.as_ref().or(self.hint.as_ref());
+        let rows = match message {
+            Some(_) => 1,
+            None => self.matches.len().saturating_sub(self.first).min(ROWS),
+        };
+        let bottom = TOP + ROW_HEIGHT * (rows + 1) as f32 + 2.0 * PAD;
+        rt.fill_rectangle((left, TOP, right, bottom), &bg);
+
+        let text_dy = (ROW_HEIGHT - 15.0) / 2.0;
+        let mut y = TOP + PAD;
+        rt.fill_rectangle((left + PAD, y, right - PAD, y + ROW_HEIGHT), &input_bg);
+        let query = format!("{}|", self.query);
+        let layout = text_layout(factory, &query, &text_format);
+        rt.draw_text_layout((left + 2.0 * PAD, y + text_dy), &layout, &fg,
+            default_text_options());
+        y += ROW_HEIGHT;
+
+        if let Some(message) = message {
+            let layout = text_layout(factory, message, &text_format);
+            let brush = if self.error.is_some() { &fg } else { &dim };
+            rt.draw_text_layout((left + 2.0 * PAD, y + text_dy), &layout, brush,
+                default_text_options());
+            return;
+        }
+        for (i, &ix) in self.matches.iter().enumerate().skip(self.first).take(ROWS) {
+            let item = &self.items[ix];
+            if i == self.selected {
+                rt.fill_rectangle((left + PAD, y, right - PAD, y + ROW_HEIGHT), &sel);
+            }
+            let layout = text_layout(factory, &item.title, &text_format);
+            rt.draw_text_layout((left + 2.0 * PAD, y + text_dy), &layout, &fg,
+                default_text_options());
+            if let Some(ref keys) = item.keys {
+                let layout = text_layout(factory, keys, &text_format);
+                let x = right - 2.0 * PAD - text_width(&layout, keys);
+                rt.draw_text_layout((x, y + text_dy), &layout, &dim, default_text_options());
+            }
+            y += ROW_HEIGHT;
+        }
+    }
+}
+
+/// Scores how well `query` matches `text` as a subsequence, ignoring case
+/// and spaces in the query, or returns None if it doesn't match. Runs of
+/// consecutive characters and matches at the start of words score higher.
+pub fn fuzzy_score(query: &str, text: &str) -> Option<i32> {
+    let text = text.chars().collect::<Vec<_>>();
+    let mut score = 0;
+    let mut pos = 0;
+    let mut prev = None;
+    for q in query.chars().filter(|c| !c.is_whitespace()).flat_map(char::to_lowercase) {
+        let found = (pos..text.len()).find(|&i| text[i].to_lowercase().eq(Some(q)))?;
+        score += 1;
+        if prev == Some(found.wrapping_sub(1)) {
+            score += 5;
+        }
+        if found == 0 || !text[found - 1].is_alphanumeric() {
+            score += 3;
+        }
+        score -= (found - pos).min(3) as i32;
+        prev = Some(found);
+        pos = found + 1;
+    }
+    Some(score)
+}
+
+/// Lays out `text` on one line, for the overlays painted over the edit view.
+pub fn text_layout(factory: &directwrite::Factory, text: &str, format: &TextFormat)
+    -> TextLayout
+{
+    TextLayout::create(factory)
+        .with_text(text)
+        .with_font(format)
+        .with_width(1e6)
+        .with_height(1e6)
+        .build().unwrap()
+}
+
+/// Width of the laid out text, in px.
+pub fn text_width(layout: &TextLayout, text: &str) -> f32 {
+    let len = text.encode_utf16().count() as u32;
+    layout.hit_test_text_position(len, true).map(|pos| pos.point_x).unwrap_or(0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(name: &str, title: &str, recent: Option<usize>) -> PaletteItem {
+        PaletteItem { name: name.to_owned(), title: title.to_owned(), keys: None, recent }
+    }
+
+    /// The names of the commands matching `query`, best first.
+    fn matches(items: &[PaletteItem], query: &str) -> Vec<String> {
+        let mut palette = Palette::new(items.to_vec());
+        for c in query.chars() {
+            palette.insert(c as u32);
+        }
+        palette.matches.iter().map(|&ix| palette.items[ix].name.clone()).collect()
+    }
+
+    #[test]
+    fn fuzzy_scores() {
+        assert_eq!(fuzzy_score("", "Save"), Some(0));
+        assert_eq!(fuzzy_score("xyz", "Save"), None);
+        assert_eq!(fuzzy_score("vs", "Save"), None);
+        // Each char scores 1, plus 5 if it follows the last one and 3 at
+        // the start of a word, minus up to 3 for the chars skipped.
+        assert_eq!(fuzzy_score("sa", "Save"), Some(1 + 3 + 1 + 5));
+        assert_eq!(fuzzy_score("SA", "save as"), Some(1 + 3 + 1 + 5));
+        assert_eq!(fuzzy_score("sa", "Save As"), fuzzy_score("s a", "Save As"));
+        assert_eq!(fuzzy_score("sas", "Save As"), Some(10 + 1 - 3));
+        assert!(fuzzy_score("sa", "Save All") > fuzzy_score("sl", "Save All"));
+    }
+
+    #[test]
+    fn ranking() {
+        let items = [
+            item("save_all", "Save All", None),
+            item("save", "Save", None),
+            item("select_all", "Select All", Some(1)),
+            item("open", "Open", Some(0)),
+        ];
+        // Recently used commands first while there's no query.
+        assert_eq!(matches(&items, ""), ["open", "select_all", "save", "save_all"]);
+        // A recent command that matches less well doesn't come first.
+        assert_eq!(matches(&items, "save"), ["save", "save_all"]);
+        assert_eq!(matches(&items, "sa"), ["save", "save_all", "select_all"]);
+    }
+
+    #[test]
+    fn recency_breaks_ties() {
+        let items = [
+            item("save_b", "Save B", None),
+            item("save_a", "Save A", None),
+            item("save_c", "Save C", Some(3)),
+            item("toggle_word_wrap", "Word Wrap", None),
+        ];
+        assert_eq!(matches(&items, "save"), ["save_c", "save_a", "save_b"]);
+        // Matches on the name count too.
+        assert_eq!(matches(&items, "toggle"), ["toggle_word_wrap"]);
+    }
+}
@@ -0,0 +1,119 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The most recently used files, shown in File > Open Recent.
+//!
+//! The list is stored as a JSON array of paths in `recent.json` in the
+//! config directory, most recent first.
+
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use serde_json;
+
+use menus::{MenuEntries, MenuItem, RECENT_BASE};
+
+/// How many files are remembered.
+const MAX_RECENT: usize = 10;
+
+#[derive(Clone, Debug, Default)]
+pub struct RecentFiles {
+    /// Where the list is saved; it isn't if there's no config directory.
+    path: Option<PathBuf>,
+    files: Vec<String>,
+}
+
+impl RecentFiles {
+    /// Loads the list from the config directory, leaving out files that
+    /// no longer exist.
+    pub fn load(config_dir: Option<&Path>) -> RecentFiles {
+        let path = config_dir.map(|dir| dir.join("recent.json"));
+        let files = path.as_ref()
+            .and_then(|path| File::open(path).ok())
+            .and_then(|file| serde_json::from_reader::<_, Vec<String>>(file).ok())
+            .unwrap_or_default();
+        let mut recent = RecentFiles { path, files };
+        recent.prune();
+        recent
+    }
+
+    pub fn files(&self) -> &[String] {
+        &self.files
+    }
+
+    /// Moves `filename` to the top of the list.
+    pub fn add(&mut self, filename: &str) {
+        self.files.retain(|f| !same_file(f, filename));
+        self.files.insert(0, filename.to_owned());
+        self.files.truncate(MAX_RECENT);
+        self.save();
+    }
+
+    pub fn clear(&mut self) {
+        self.files.clear();
+        self.save();
+    }
+
+    /// The items of the Open Recent menu.
+    pub fn menu_items(&self) -> Vec<MenuItem> {
+        let mut items: Vec<_> = self.files.iter().enumerate()
+            .map(|(i, filename)| MenuItem {
+                id: RECENT_BASE + i as u32,
+                // Keep `&` from being taken as a mnemonic.
+                text: filename.replace('&', "&&"),
+                checked: false,
+                enabled: true,
+            })
+            .collect();
+        if !items.is_empty() {
+            items.push(MenuItem::separator());
+        }
+        items.push(MenuItem {
+            id: MenuEntries::ClearRecent as u32,
+            text: "&Clear Recent".to_owned(),
+            checked: false,
+            enabled: !self.files.is_empty(),
+        });
+        items
+    }
+
+    /// Leaves out files that no longer exist. This looks at every file,
+    /// which can be slow on network drives, so it's only done on load and
+    /// when one of them turns out to be gone.
+    pub fn prune(&mut self) {
+        let len = self.files.len();
+        self.files.retain(|filename| Path::new(filename).is_file());
+        if self.files.len() != len {
+            self.save();
+        }
+    }
+
+    fn save(&self) {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return,
+        };
+        let result = serde_json::to_string_pretty(&self.files)
+            .map_err(|e| e.to_string())
+            .and_then(|json| fs::write(path, json).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            warn!("could not save recent files to {:?}: {}", path, e);
+        }
+    }
+}
+
+/// Whether two paths name the same file, ignoring case as Windows does.
+fn same_file(a: &str, b: &str) -> bool {
+    a.to_lowercase() == b.to_lowercase()
+}
@@ -0,0 +1,108 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Recording of the RPC traffic between the front end and the core.
+//!
+//! A recording is a file with one JSON object per line:
+//! `{"time": ms since start, "dir": "to_core" | "from_core", "msg": ...}`.
+//! It can be replayed without Windows by the `xi-replay` tool in `replay/`,
+//! which shares this module.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use serde_json::{self, Value};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    ToCore,
+    FromCore,
+}
+
+impl Direction {
+    fn as_str(self) -> &'static str {
+        match self {
+            Direction::ToCore => "to_core",
+            Direction::FromCore => "from_core",
+        }
+    }
+}
+
+/// A recorded message.
+#[allow(dead_code)] // only used by xi-replay
+#[derive(Clone, Debug)]
+pub struct Entry {
+    /// Milliseconds since the recording started.
+    pub time: u64,
+    pub direction: Direction,
+    pub msg: Value,
+}
+
+/// Writes messages to a recording file. Can be shared between threads.
+pub struct Recorder {
+    start: Instant,
+    out: Mutex<BufWriter<File>>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> io::Result<Recorder> {
+        Ok(Recorder {
+            start: Instant::now(),
+            out: Mutex::new(BufWriter::new(File::create(path)?)),
+        })
+    }
+
+    pub fn record(&self, direction: Direction, msg: &Value) {
+        let elapsed = self.start.elapsed();
+        let time = elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis());
+        let entry = json!({
+            "time": time,
+            "dir": direction.as_str(),
+            "msg": msg,
+        });
+        let mut out = self.out.lock().unwrap();
+        // Flush every line so the recording survives a crash.
+        let _ = writeln!(out, "{}", entry).and_then(|_| out.flush());
+    }
+}
+
+/// Reads a recording. Lines that aren't valid entries are reported as errors.
+#[allow(dead_code)] // only used by xi-replay
+pub fn read_recording(path: &Path) -> io::Result<Vec<Entry>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut entries = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData,
+            format!("line {}: {}", i + 1, what));
+        let v: Value = serde_json::from_str(&line).map_err(|e| invalid(&e.to_string()))?;
+        let direction = match v["dir"].as_str() {
+            Some("to_core") => Direction::ToCore,
+            Some("from_core") => Direction::FromCore,
+            _ => return Err(invalid("bad \"dir\"")),
+        };
+        entries.push(Entry {
+            time: v["time"].as_u64().unwrap_or(0),
+            direction,
+            msg: v["msg"].clone(),
+        });
+    }
+    Ok(entries)
+}
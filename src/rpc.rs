@@ -15,12 +15,20 @@
 //! Front-end side implementation of RPC protocol.
 
 use std::collections::BTreeMap;
-use std::sync::{Arc, Mutex};
-use std::sync::mpsc::Receiver;
-use std::thread;
+use std::fmt;
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, JoinHandle, Thread};
+use std::time::{Duration, Instant};
 
 use serde_json::Value;
 
+use logging::RPC_TARGET;
+use recorder::{Direction, Recorder};
 use xi_thread::XiPeer;
 
 #[derive(Clone)]
@@ -29,21 +37,214 @@ pub struct Core {
 }
 
 struct CoreState {
-    xi_peer: XiPeer,
+    /// None once the connection has been shut down.
+    xi_peer: Option<XiPeer>,
     id: u64,
-    pending: BTreeMap<u64, Box<Callback>>,
+    pending: BTreeMap<u64, Pending>,
+    /// What to call if a request that timed out is answered after all.
+    late: BTreeMap<u64, Box<Callback>>,
+    recorder: Option<Arc<Recorder>>,
+    /// False once the core has gone away.
+    connected: bool,
+    rx_thread: Option<JoinHandle<()>>,
+    /// Disconnected when the receive thread ends.
+    rx_done: Option<Receiver<()>>,
+}
+
+struct Pending {
+    callback: Box<Callback>,
+    deadline: Option<Instant>,
+    /// Moved to `CoreState::late` when the deadline passes.
+    late: Option<Box<Callback>>,
+}
+
+/// How often the receive thread checks for timed out requests.
+const TIMEOUT_POLL: Duration = Duration::from_millis(100);
+
+impl CoreState {
+    fn send(&self, cmd: &Value) {
+        let xi_peer = match self.xi_peer {
+            Some(ref xi_peer) if self.connected => xi_peer,
+            _ => {
+                warn!("core disconnected, dropping {}", cmd["method"]);
+                return;
+            }
+        };
+        trace!(target: RPC_TARGET, "fe->core: {}", cmd);
+        if let Some(ref recorder) = self.recorder {
+            recorder.record(Direction::ToCore, cmd);
+        }
+        xi_peer.send_json(cmd);
+    }
+}
+
+/// An error response to a request.
+#[derive(Clone, Debug)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+    pub data: Option<Value>,
+}
+
+/// Error codes for failures on our side, in the range JSON-RPC reserves for
+/// implementations.
+const TIMED_OUT: i64 = -32001;
+const DISCONNECTED: i64 = -32002;
+
+/// The JSON-RPC error code for requests with a method we don't handle.
+pub const METHOD_NOT_FOUND: i64 = -32601;
+/// The JSON-RPC error code for requests with params we can't make sense of.
+pub const INVALID_PARAMS: i64 = -32602;
+
+impl RpcError {
+    pub fn new(code: i64, message: &str) -> RpcError {
+        RpcError {
+            code,
+            message: message.to_owned(),
+            data: None,
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        let mut error = json!({
+            "code": self.code,
+            "message": self.message,
+        });
+        if let Some(ref data) = self.data {
+            error["data"] = data.clone();
+        }
+        error
+    }
+
+    /// Parses the `error` field of a response.
+    fn from_json(v: &Value) -> RpcError {
+        RpcError {
+            code: v["code"].as_i64().unwrap_or(0),
+            message: v["message"].as_str().unwrap_or("unknown error").to_owned(),
+            data: if v["data"].is_null() { None } else { Some(v["data"].clone()) },
+        }
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        if let Some(ref data) = self.data {
+            if let Some(data) = data.as_str() {
+                write!(f, ": {}", data)?;
+            }
+        }
+        Ok(())
+    }
 }
 
 trait Callback: Send {
-    fn call(self: Box<Self>, result: &Value);
+    fn call(self: Box<Self>, result: Result<Value, RpcError>);
+}
+
+/// A request that hasn't been answered yet.
+pub struct PendingRequest {
+    id: u64,
+    state: Weak<Mutex<CoreState>>,
+}
+
+impl PendingRequest {
+    /// Drops the request's callback without calling it, and ignores any
+    /// later response. Returns false if the request had already finished.
+    pub fn cancel(self) -> bool {
+        let callback = match self.state.upgrade() {
+            Some(state) => {
+                let mut state = state.lock().unwrap();
+                state.late.remove(&self.id);
+                state.pending.remove(&self.id)
+            }
+            None => None,
+        };
+        // Dropped here, outside the lock.
+        callback.is_some()
+    }
+}
+
+/// The result of `send_request_async`. It doesn't need any particular
+/// executor: the receive thread wakes whatever task is polling it.
+///
+/// Dropping it before it's done cancels the request.
+pub struct ResponseFuture {
+    shared: Arc<Mutex<ResponseState>>,
+    request: Option<PendingRequest>,
+}
+
+#[derive(Default)]
+struct ResponseState {
+    result: Option<Result<Value, RpcError>>,
+    waker: Option<Waker>,
+}
+
+impl Future for ResponseFuture {
+    type Output = Result<Value, RpcError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut shared = self.shared.lock().unwrap();
+        match shared.result.take() {
+            Some(result) => {
+                drop(shared);
+                self.request = None;
+                Poll::Ready(result)
+            }
+            None => {
+                shared.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for ResponseFuture {
+    fn drop(&mut self) {
+        if let Some(request) = self.request.take() {
+            request.cancel();
+        }
+    }
+}
+
+/// Runs `future` to completion on the current thread, parking it while the
+/// future is pending.
+///
+/// This must not be called from the UI thread or from a request callback,
+/// since those are what make progress.
+pub fn block_on<F: Future + Unpin>(mut future: F) -> F::Output {
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        match Pin::new(&mut future).poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
 }
 
 pub trait Handler {
     fn notification(&self, method: &str, params: &Value);
+
+    /// Answers a request from the core. The result or error is sent back
+    /// as the response.
+    fn request(&self, method: &str, params: &Value) -> Result<Value, RpcError>;
+
+    /// Called once the core has gone away, after the pending requests
+    /// have been failed.
+    fn disconnected(&self);
 }
 
-impl<F: FnOnce(&Value) + Send> Callback for F {
-    fn call(self: Box<F>, result: &Value) {
+impl<F: FnOnce(Result<Value, RpcError>) + Send> Callback for F {
+    fn call(self: Box<F>, result: Result<Value, RpcError>) {
         (*self)(result)
     }
 }
@@ -52,59 +253,325 @@ impl Core {
     /// Sets up a new RPC connection, also starting a thread to receive
     /// responses.
     ///
-    /// The handler is invoked for incoming RPC notifications. Note that
-    /// it must be `Send` because it is called from a dedicated thread.
-    pub fn new<H>(xi_peer: XiPeer, rx: Receiver<Value>, handler: H) -> Core
+    /// The handler is invoked for incoming RPC notifications and requests.
+    /// Note that it must be `Send` because it is called from a dedicated
+    /// thread.
+    ///
+    /// If there is a recorder, all messages in both directions are recorded.
+    pub fn new<H>(xi_peer: XiPeer, rx: Receiver<Value>, handler: H,
+        recorder: Option<Arc<Recorder>>) -> Core
         where H: Handler + Send + 'static
     {
+        let (done_tx, rx_done) = channel::<()>();
         let state = CoreState {
-            xi_peer,
+            xi_peer: Some(xi_peer),
             id: 0,
             pending: BTreeMap::new(),
+            late: BTreeMap::new(),
+            recorder: recorder.clone(),
+            connected: true,
+            rx_thread: None,
+            rx_done: Some(rx_done),
         };
         let core = Core { state: Arc::new(Mutex::new(state)) };
         let rx_core_handle = core.clone();
-        thread::spawn(move || {
-            while let Ok(msg) = rx.recv() {
+        let rx_thread = thread::spawn(move || {
+            let _done = done_tx;
+            loop {
+                let msg = match rx.recv_timeout(TIMEOUT_POLL) {
+                    Ok(msg) => msg,
+                    Err(RecvTimeoutError::Timeout) => {
+                        rx_core_handle.expire_requests();
+                        continue;
+                    }
+                    Err(RecvTimeoutError::Disconnected) => break,
+                };
+                trace!(target: RPC_TARGET, "core->fe: {}", msg);
+                if let Some(ref recorder) = recorder {
+                    recorder.record(Direction::FromCore, &msg);
+                }
                 if let Value::String(ref method) = msg["method"] {
-                    handler.notification(&method, &msg["params"]);
+                    if msg["id"].is_null() {
+                        handler.notification(&method, &msg["params"]);
+                    } else {
+                        let mut response = json!({ "id": msg["id"] });
+                        match handler.request(&method, &msg["params"]) {
+                            Ok(result) => response["result"] = result,
+                            Err(e) => response["error"] = e.to_json(),
+                        }
+                        rx_core_handle.state.lock().unwrap().send(&response);
+                    }
                 } else if let Some(id) = msg["id"].as_u64() {
-                    let mut state = rx_core_handle.state.lock().unwrap();
-                    if let Some(callback) = state.pending.remove(&id) {
-                        callback.call(&msg["result"]);
+                    // Release the lock first, so the callback can send requests.
+                    let callback = {
+                        let mut state = rx_core_handle.state.lock().unwrap();
+                        match state.pending.remove(&id) {
+                            Some(pending) => Some(pending.callback),
+                            None => state.late.remove(&id),
+                        }
+                    };
+                    if let Some(callback) = callback {
+                        let result = if msg["error"].is_null() {
+                            Ok(msg["result"].clone())
+                        } else {
+                            Err(RpcError::from_json(&msg["error"]))
+                        };
+                        callback.call(result);
                     } else {
-                        println!("unexpected result")
+                        debug!("result for finished request {}", id);
                     }
                 } else {
-                    println!("got {:?} at rpc level", msg);
+                    warn!("got {:?} at rpc level", msg);
                 }
+                rx_core_handle.expire_requests();
+            }
+            warn!("core disconnected");
+            let (pending, _late) = {
+                let mut state = rx_core_handle.state.lock().unwrap();
+                state.connected = false;
+                (mem::replace(&mut state.pending, BTreeMap::new()),
+                    mem::replace(&mut state.late, BTreeMap::new()))
+            };
+            for (_, pending) in pending {
+                pending.callback.call(Err(RpcError::new(DISCONNECTED, "core disconnected")));
             }
+            handler.disconnected();
         });
+        core.state.lock().unwrap().rx_thread = Some(rx_thread);
         core
     }
 
+    /// Closes the connection. The core exits once it has handled the
+    /// messages sent so far; this waits up to `timeout` for that, and then
+    /// joins the threads. Returns false if the core didn't exit in time.
+    pub fn shutdown(&self, timeout: Duration) -> bool {
+        let (xi_peer, rx_thread, rx_done) = {
+            let mut state = self.state.lock().unwrap();
+            (state.xi_peer.take(), state.rx_thread.take(), state.rx_done.take())
+        };
+        let threads = xi_peer.map(XiPeer::close).unwrap_or_default();
+        let done = match rx_done {
+            Some(rx_done) => rx_done.recv_timeout(timeout) == Err(RecvTimeoutError::Disconnected),
+            None => false,
+        };
+        if done {
+            for thread in threads.into_iter().chain(rx_thread) {
+                let _ = thread.join();
+            }
+        }
+        done
+    }
+
+    /// The recorder given to `new`, so a new connection can share it.
+    pub fn recorder(&self) -> Option<Arc<Recorder>> {
+        self.state.lock().unwrap().recorder.clone()
+    }
+
     pub fn send_notification(&self, method: &str, params: &Value) {
         let cmd = json!({
             "method": method,
             "params": params,
         });
-        let state = self.state.lock().unwrap();
-        state.xi_peer.send_json(&cmd);
+        self.state.lock().unwrap().send(&cmd);
     }
 
-    /// Calls the callback with the result (from a different thread).
+    /// Calls the callback with the result or error (from a different thread).
     pub fn send_request<F>(&mut self, method: &str, params: &Value, callback: F)
-        where F: FnOnce(&Value) + Send + 'static
+        -> PendingRequest
+        where F: FnOnce(Result<Value, RpcError>) + Send + 'static
+    {
+        self.send_request_impl(method, params, None, Box::new(callback), None)
+    }
+
+    /// Like `send_request`, but the callback gets an error if there's no
+    /// response within `timeout`. A response that comes after that goes to
+    /// `late` instead, so that whatever the core did can be undone.
+    pub fn send_request_with_timeout<F, L>(&mut self, method: &str, params: &Value,
+        timeout: Duration, callback: F, late: L) -> PendingRequest
+        where F: FnOnce(Result<Value, RpcError>) + Send + 'static,
+              L: FnOnce(Result<Value, RpcError>) + Send + 'static
+    {
+        self.send_request_impl(method, params, Some(timeout), Box::new(callback),
+            Some(Box::new(late)))
+    }
+
+    fn send_request_impl(&mut self, method: &str, params: &Value, timeout: Option<Duration>,
+        callback: Box<Callback>, late: Option<Box<Callback>>) -> PendingRequest
     {
         let mut state = self.state.lock().unwrap();
         let id = state.id;
+        state.id += 1;
+        let request = PendingRequest { id, state: Arc::downgrade(&self.state) };
+        if !state.connected {
+            // Still call back from a different thread, the caller may hold locks.
+            thread::spawn(move ||
+                callback.call(Err(RpcError::new(DISCONNECTED, "core disconnected"))));
+            return request;
+        }
         let cmd = json!({
             "method": method,
             "params": params,
             "id": id,
         });
-        state.xi_peer.send_json(&cmd);
-        state.pending.insert(id, Box::new(callback));
-        state.id += 1;
+        state.send(&cmd);
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        state.pending.insert(id, Pending { callback, deadline, late });
+        request
+    }
+
+    /// Like `send_request`, but returns a future instead of taking a
+    /// callback, so that requests depending on each other can be written
+    /// as a sequence of `.await`s, or of `block_on`s on a thread of their
+    /// own, instead of nested callbacks.
+    pub fn send_request_async(&mut self, method: &str, params: &Value) -> ResponseFuture {
+        let shared = Arc::new(Mutex::new(ResponseState::default()));
+        let callback_shared = shared.clone();
+        let request = self.send_request(method, params, move |result| {
+            let waker = {
+                let mut state = callback_shared.lock().unwrap();
+                state.result = Some(result);
+                state.waker.take()
+            };
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        });
+        ResponseFuture { shared, request: Some(request) }
+    }
+
+    /// Fails the requests whose deadline has passed.
+    fn expire_requests(&self) {
+        let now = Instant::now();
+        let expired = {
+            let mut state = self.state.lock().unwrap();
+            let ids: Vec<u64> = state.pending.iter()
+                .filter(|&(_, pending)| pending.deadline.map_or(false, |d| d <= now))
+                .map(|(&id, _)| id)
+                .collect();
+            let mut expired = Vec::new();
+            for id in ids {
+                if let Some(mut pending) = state.pending.remove(&id) {
+                    if let Some(late) = pending.late.take() {
+                        state.late.insert(id, late);
+                    }
+                    expired.push(pending);
+                }
+            }
+            expired
+        };
+        for pending in expired {
+            pending.callback.call(Err(RpcError::new(TIMED_OUT, "request timed out")));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc::Sender;
+
+    struct NoHandler;
+
+    impl Handler for NoHandler {
+        fn notification(&self, _method: &str, _params: &Value) {}
+
+        fn request(&self, method: &str, _params: &Value) -> Result<Value, RpcError> {
+            Err(RpcError::new(METHOD_NOT_FOUND, method))
+        }
+
+        fn disconnected(&self) {}
+    }
+
+    /// A `Core` talking to channels instead of a core. Returns what the
+    /// front end sends, and where to send the core's side.
+    fn fake_core() -> (Core, Receiver<String>, Sender<Value>) {
+        let (xi_peer, sent) = XiPeer::in_memory();
+        let (to_fe, rx) = channel();
+        (Core::new(xi_peer, rx, NoHandler, None), sent, to_fe)
+    }
+
+    /// The next request the front end sent, checking its method.
+    fn next_request(sent: &Receiver<String>, method: &str) -> Value {
+        let request: Value = ::serde_json::from_str(&sent.recv().unwrap()).unwrap();
+        assert_eq!(request["method"], json!(method));
+        request
+    }
+
+    struct NoWaker;
+
+    impl Wake for NoWaker {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    fn poll(future: &mut ResponseFuture) -> Poll<Result<Value, RpcError>> {
+        let waker = Waker::from(Arc::new(NoWaker));
+        Pin::new(future).poll(&mut Context::from_waker(&waker))
+    }
+
+    #[test]
+    fn async_result() {
+        let (mut core, sent, to_fe) = fake_core();
+        let mut future = core.send_request_async("new_view", &json!({}));
+        let request = next_request(&sent, "new_view");
+        assert!(poll(&mut future).is_pending());
+        to_fe.send(json!({ "id": request["id"], "result": "view-id-1" })).unwrap();
+        match block_on(future) {
+            Ok(result) => assert_eq!(result, json!("view-id-1")),
+            Err(e) => panic!("unexpected error {}", e),
+        }
+    }
+
+    #[test]
+    fn async_error() {
+        let (mut core, sent, to_fe) = fake_core();
+        let future = core.send_request_async("new_view", &json!({ "file_path": "nope" }));
+        let request = next_request(&sent, "new_view");
+        to_fe.send(json!({
+            "id": request["id"],
+            "error": { "code": 42, "message": "no such file" },
+        })).unwrap();
+        match block_on(future) {
+            Ok(result) => panic!("unexpected result {}", result),
+            Err(e) => {
+                assert_eq!(e.code, 42);
+                assert_eq!(e.message, "no such file");
+            }
+        }
+    }
+
+    #[test]
+    fn late_response() {
+        let (mut core, sent, to_fe) = fake_core();
+        let (results, result_rx) = channel();
+        let late_results = results.clone();
+        core.send_request_with_timeout("new_view", &json!({}), Duration::from_millis(10),
+            move |result| results.send(("callback", result)).unwrap(),
+            move |result| late_results.send(("late", result)).unwrap());
+        let request = next_request(&sent, "new_view");
+        match result_rx.recv().unwrap() {
+            ("callback", Err(e)) => assert_eq!(e.code, TIMED_OUT),
+            (to, result) => panic!("unexpected {} {:?}", to, result),
+        }
+        to_fe.send(json!({ "id": request["id"], "result": "view-id-1" })).unwrap();
+        match result_rx.recv().unwrap() {
+            ("late", Ok(result)) => assert_eq!(result, json!("view-id-1")),
+            (to, result) => panic!("unexpected {} {:?}", to, result),
+        }
+    }
+
+    #[test]
+    fn async_disconnect() {
+        let (mut core, sent, to_fe) = fake_core();
+        let future = core.send_request_async("new_view", &json!({}));
+        next_request(&sent, "new_view");
+        drop(to_fe);
+        match block_on(future) {
+            Ok(result) => panic!("unexpected result {}", result),
+            Err(e) => assert_eq!(e.code, DISCONNECTED),
+        }
+        // Requests made once the core is gone fail too.
+        let future = core.send_request_async("new_view", &json!({}));
+        assert_eq!(block_on(future).map_err(|e| e.code), Err(DISCONNECTED));
     }
 }
@@ -0,0 +1,124 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! User configuration: the config directory and the Settings menu.
+
+use std::env;
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use menus::{MenuEntries, MenuItem};
+
+/// Where a settings change made from the menu is stored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Domain {
+    /// The user's general preferences.
+    General,
+    /// Preferences for the focused view's language.
+    Language,
+    /// An override for the focused view only.
+    View,
+}
+
+impl Domain {
+    /// The `domain` parameter of `modify_user_config` for a change made in
+    /// the given view, or `None` for `Language` when the view has none.
+    pub fn to_json(self, view_id: &str, language: Option<&str>) -> Option<Value> {
+        match self {
+            Domain::General => Some(json!("general")),
+            Domain::Language => language.map(|language| json!({ "syntax": language })),
+            Domain::View => Some(json!({ "user_override": view_id })),
+        }
+    }
+}
+
+const TAB_SIZES: &[(MenuEntries, u64)] = &[
+    (MenuEntries::TabSize2, 2),
+    (MenuEntries::TabSize4, 4),
+    (MenuEntries::TabSize8, 8),
+];
+
+const FLAGS: &[(MenuEntries, &str, &str)] = &[
+    (MenuEntries::TranslateTabs, "translate_tabs_to_spaces", "Translate Tabs to Spaces"),
+    (MenuEntries::WordWrap, "word_wrap", "Word Wrap"),
+    (MenuEntries::AutoIndent, "auto_indent", "Auto-Indent"),
+];
+
+/// The directory for the user's configuration: `%APPDATA%\xi`.
+pub fn config_dir() -> Option<PathBuf> {
+    env::var_os("APPDATA").map(|appdata| PathBuf::from(appdata).join("xi"))
+}
+
+/// The user's preferences file, read by the core from the config directory.
+pub fn preferences_path(config_dir: &Path) -> PathBuf {
+    config_dir.join("preferences.xiconfig")
+}
+
+/// The dynamic part of the Settings menu, showing the focused view's
+/// `config` and the selected `domain`. `language` is the focused view's
+/// language, if known.
+pub fn menu_items(config: &Value, domain: Domain, language: Option<&str>) -> Vec<MenuItem> {
+    let mut items = Vec::new();
+    let language_text = match language {
+        Some(language) => format!("Apply to {} Files", language),
+        None => "Apply to Current Language".to_owned(),
+    };
+    items.push(item(MenuEntries::DomainGeneral, "Apply to All Files".to_owned(),
+        domain == Domain::General));
+    let mut language_item = item(MenuEntries::DomainLanguage, language_text,
+        domain == Domain::Language);
+    // Language settings need a language to apply to.
+    language_item.enabled = language.is_some();
+    items.push(language_item);
+    items.push(item(MenuEntries::DomainView, "Apply to This View".to_owned(),
+        domain == Domain::View));
+    items.push(MenuItem::separator());
+    for &(entry, size) in TAB_SIZES {
+        items.push(item(entry, format!("Tab Size {}", size),
+            config["tab_size"].as_u64() == Some(size)));
+    }
+    items.push(MenuItem::separator());
+    for &(entry, key, text) in FLAGS {
+        items.push(item(entry, text.to_owned(), config[key].as_bool() == Some(true)));
+    }
+    items.push(MenuItem::separator());
+    // The font and everything else is set in the settings dialog.
+    items.push(item(MenuEntries::SettingsDialog, "&Edit Settings…".to_owned(), false));
+    items
+}
+
+/// The config changes requested by a Settings menu command, given the
+/// focused view's current `config`.
+pub fn changes_for_cmd(cmd: u32, config: &Value) -> Option<Value> {
+    if let Some(&(_, size)) = TAB_SIZES.iter().find(|&&(e, _)| e as u32 == cmd) {
+        return Some(json!({ "tab_size": size }));
+    }
+    if let Some(&(_, key, _)) = FLAGS.iter().find(|&&(e, _, _)| e as u32 == cmd) {
+        let enabled = config[key].as_bool().unwrap_or(false);
+        let mut changes = json!({});
+        changes[key] = json!(!enabled);
+        return Some(changes);
+    }
+    None
+}
+
+fn item(entry: MenuEntries, text: String, checked: bool) -> MenuItem {
+    MenuItem {
+        id: entry as u32,
+        text,
+        checked,
+        enabled: true,
+    }
+}
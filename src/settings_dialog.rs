@@ -0,0 +1,317 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The settings dialog, shown over the edit view like the command palette,
+//! to edit the focused view's settings and choose where they're stored.
+
+use winapi::um::winuser::*;
+
+use direct2d::RenderTarget;
+use directwrite;
+use directwrite::TextFormat;
+
+use serde_json::Value;
+
+use druid_win_shell::util::default_text_options;
+use druid_win_shell::window::M_SHIFT;
+
+use palette::{text_layout, text_width};
+use settings::Domain;
+use theme::{solid_brush, Theme};
+
+const ROW_HEIGHT: f32 = 22.0;
+const WIDTH: f32 = 420.0;
+const PAD: f32 = 6.0;
+const TOP: f32 = 8.0;
+/// Where the values start, from the left of the dialog.
+const VALUE_X: f32 = 200.0;
+
+const HINT: &str = "Space or arrows to change, Enter to apply, Esc to cancel";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    /// A whole number in the given range.
+    Number(u64, u64),
+    Flag,
+    Text,
+}
+
+/// The settings in the dialog: config key, label and kind.
+const FIELDS: &[(&str, &str, Kind)] = &[
+    ("tab_size", "Tab size", Kind::Number(1, 16)),
+    ("translate_tabs_to_spaces", "Translate tabs to spaces", Kind::Flag),
+    ("auto_indent", "Auto-indent", Kind::Flag),
+    ("word_wrap", "Word wrap", Kind::Flag),
+    ("font_face", "Font", Kind::Text),
+    ("font_size", "Font size", Kind::Number(6, 72)),
+];
+
+/// What a key pressed in the dialog leads to.
+pub enum DialogKey {
+    Continue,
+    Close,
+    /// Store the changed settings in the domain.
+    Apply(Domain, Value),
+}
+
+pub struct SettingsDialog {
+    title: String,
+    /// The view's language, if it has one.
+    language: Option<String>,
+    domain: Domain,
+    /// The view's config when the dialog was opened.
+    config: Value,
+    /// The value of each of the `FIELDS`, as edited.
+    values: Vec<Value>,
+    /// 0 is the domain, the fields follow.
+    selected: usize,
+    /// Shown instead of the hint until something changes.
+    error: Option<String>,
+}
+
+impl SettingsDialog {
+    /// A dialog for a view with the given `config`, storing changes in
+    /// `domain` unless another is chosen.
+    pub fn new(title: String, config: Value, domain: Domain, language: Option<String>)
+        -> SettingsDialog
+    {
+        let values = FIELDS.iter().map(|&(key, _, kind)| match kind {
+            Kind::Number(_, _) => json!(config[key].as_f64().map_or(String::new(),
+                |n| (n.round() as u64).to_string())),
+            Kind::Flag => json!(config[key].as_bool().unwrap_or(false)),
+            Kind::Text => json!(config[key].as_str().unwrap_or("")),
+        }).collect();
+        let domain = match domain {
+            Domain::Language if language.is_none() => Domain::General,
+            domain => domain,
+        };
+        SettingsDialog {
+            title,
+            language,
+            domain,
+            config,
+            values,
+            selected: 1,
+            error: None,
+        }
+    }
+
+    /// Shows why the settings couldn't be applied, until something changes.
+    pub fn set_error(&mut self, error: String) {
+        self.error = Some(error);
+    }
+
+    /// The edited field and its kind, unless the domain is selected.
+    fn field(&mut self) -> Option<(Kind, &mut Value)> {
+        match self.selected {
+            0 => None,
+            ix => Some((FIELDS[ix - 1].2, &mut self.values[ix - 1])),
+        }
+    }
+
+    /// Adds a typed character to the selected number or text.
+    pub fn insert(&mut self, ch: u32) {
+        let c = match ::std::char::from_u32(ch) {
+            Some(c) if ch >= 0x20 => c,
+            _ => return,
+        };
+        let changed = match self.field() {
+            Some((Kind::Number(_, _), value)) if c.is_ascii_digit() => {
+                let text = format!("{}{}", value.as_str().unwrap_or(""), c);
+                *value = json!(text);
+                true
+            }
+            Some((Kind::Text, value)) => {
+                let text = format!("{}{}", value.as_str().unwrap_or(""), c);
+                *value = json!(text);
+                true
+            }
+            _ => false,
+        };
+        if changed {
+            self.error = None;
+        }
+    }
+
+    pub fn keydown(&mut self, vk_code: i32, mods: u32) -> DialogKey {
+        match vk_code {
+            VK_ESCAPE => return DialogKey::Close,
+            VK_RETURN => return match self.changes() {
+                Ok(changes) => DialogKey::Apply(self.domain, changes),
+                Err(e) => {
+                    self.error = Some(e);
+                    DialogKey::Continue
+                }
+            },
+            VK_UP => self.select(-1),
+            VK_DOWN => self.select(1),
+            VK_TAB => self.select(if mods == M_SHIFT { -1 } else { 1 }),
+            VK_LEFT => self.change(-1),
+            VK_RIGHT | VK_SPACE => self.change(1),
+            VK_BACK => {
+                match self.field() {
+                    Some((Kind::Number(_, _), value)) | Some((Kind::Text, value)) => {
+                        let mut text = value.as_str().unwrap_or("").to_owned();
+                        text.pop();
+                        *value = json!(text);
+                    }
+                    _ => (),
+                }
+                self.error = None;
+            }
+            _ => (),
+        }
+        DialogKey::Continue
+    }
+
+    /// Moves the selection `delta` rows, wrapping around.
+    fn select(&mut self, delta: isize) {
+        let rows = FIELDS.len() as isize + 1;
+        self.selected = ((self.selected as isize + delta + rows) % rows) as usize;
+    }
+
+    /// Changes the selected domain, flag or number by a step in the
+    /// direction of `delta`.
+    fn change(&mut self, delta: i64) {
+        self.error = None;
+        if self.selected == 0 {
+            let domains = if self.language.is_some() {
+                &[Domain::General, Domain::Language, Domain::View][..]
+            } else {
+                &[Domain::General, Domain::View][..]
+            };
+            let ix = domains.iter().position(|&d| d == self.domain).unwrap_or(0) as i64;
+            let n = domains.len() as i64;
+            self.domain = domains[((ix + delta + n) % n) as usize];
+            return;
+        }
+        match self.field() {
+            Some((Kind::Flag, value)) => {
+                let enabled = value.as_bool().unwrap_or(false);
+                *value = json!(!enabled);
+            }
+            Some((Kind::Number(min, max), value)) => {
+                let n = value.as_str().and_then(|s| s.parse::<u64>().ok()).unwrap_or(min);
+                let n = if delta < 0 { n.saturating_sub(1) } else { n.saturating_add(1) };
+                *value = json!(n.max(min).min(max).to_string());
+            }
+            Some((Kind::Text, _)) | None => (),
+        }
+    }
+
+    /// The settings that differ from the view's config, or what's wrong
+    /// with them.
+    fn changes(&self) -> Result<Value, String> {
+        let mut changes = json!({});
+        for (&(key, label, kind), value) in FIELDS.iter().zip(&self.values) {
+            let value = match kind {
+                Kind::Number(min, max) => {
+                    let n = value.as_str().and_then(|s| s.parse::<u64>().ok())
+                        .filter(|&n| n >= min && n <= max)
+                        .ok_or_else(|| format!("{} should be from {} to {}", label, min, max))?;
+                    if self.config[key].as_f64().map(|old| old.round() as u64) == Some(n) {
+                        continue;
+                    }
+                    json!(n)
+                }
+                Kind::Text => {
+                    let text = value.as_str().unwrap_or("").trim();
+                    if text.is_empty() {
+                        return Err(format!("{} should not be empty", label));
+                    }
+                    json!(text)
+                }
+                Kind::Flag => value.clone(),
+            };
+            if self.config[key] != value {
+                changes[key] = value;
+            }
+        }
+        Ok(changes)
+    }
+
+    fn domain_text(&self) -> String {
+        match (self.domain, self.language.as_ref()) {
+            (Domain::General, _) => "All Files".to_owned(),
+            (Domain::Language, Some(language)) => format!("{} Files", language),
+            (Domain::Language, None) => "Current Language".to_owned(),
+            (Domain::View, _) => "This View".to_owned(),
+        }
+    }
+
+    /// Paints the dialog at the top of an area `width` px wide.
+    pub fn paint<R: RenderTarget>(&self, rt: &mut R, factory: &directwrite::Factory,
+        theme: &Theme, width: f32)
+    {
+        let text_format = TextFormat::create(factory)
+            .with_family("Segoe UI")
+            .with_size(12.0)
+            .build()
+            .unwrap();
+        let bg = solid_brush(rt, theme.line_highlight);
+        let sel = solid_brush(rt, theme.selection);
+        let fg = solid_brush(rt, theme.foreground);
+        let dim = solid_brush(rt, theme.foreground.with_alpha(0.6));
+
+        let box_width = WIDTH.min(width - 2.0 * PAD);
+        let left = ((width - box_width) / 2.0).round();
+        let right = left + box_width;
+        // The title, the domain, the fields and the hint.
+        let rows = FIELDS.len() + 3;
+        let bottom = TOP + ROW_HEIGHT * rows as f32 + 2.0 * PAD;
+        rt.fill_rectangle((left, TOP, right, bottom), &bg);
+
+        let text_dy = (ROW_HEIGHT - 15.0) / 2.0;
+        let mut y = TOP + PAD;
+        let layout = text_layout(factory, &self.title, &text_format);
+        rt.draw_text_layout((left + 2.0 * PAD, y + text_dy), &layout, &dim,
+            default_text_options());
+        y += ROW_HEIGHT;
+
+        let mut rows = vec![("Apply to", self.domain_text(), false)];
+        for (&(_, label, kind), value) in FIELDS.iter().zip(&self.values) {
+            let (text, editable) = match kind {
+                Kind::Flag => {
+                    let text = if value.as_bool() == Some(true) { "On" } else { "Off" };
+                    (text.to_owned(), false)
+                }
+                Kind::Number(_, _) | Kind::Text => {
+                    (value.as_str().unwrap_or("").to_owned(), true)
+                }
+            };
+            rows.push((label, text, editable));
+        }
+        for (i, (label, text, editable)) in rows.into_iter().enumerate() {
+            if i == self.selected {
+                rt.fill_rectangle((left + PAD, y, right - PAD, y + ROW_HEIGHT), &sel);
+            }
+            let layout = text_layout(factory, label, &text_format);
+            rt.draw_text_layout((left + 2.0 * PAD, y + text_dy), &layout, &fg,
+                default_text_options());
+            let text = if editable && i == self.selected { format!("{}|", text) } else { text };
+            let layout = text_layout(factory, &text, &text_format);
+            let x = (left + VALUE_X).min(right - 2.0 * PAD - text_width(&layout, &text));
+            rt.draw_text_layout((x, y + text_dy), &layout, &fg, default_text_options());
+            y += ROW_HEIGHT;
+        }
+
+        let (message, brush) = match self.error {
+            Some(ref error) => (error.as_str(), &fg),
+            None => (HINT, &dim),
+        };
+        let layout = text_layout(factory, message, &text_format);
+        rt.draw_text_layout((left + 2.0 * PAD, y + text_dy), &layout, brush,
+            default_text_options());
+    }
+}
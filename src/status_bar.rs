@@ -0,0 +1,237 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The status bar at the bottom of the window.
+
+use std::any::Any;
+
+use serde_json::Value;
+
+use direct2d::RenderTarget;
+use directwrite;
+use directwrite::{TextFormat, TextLayout};
+
+use druid_win_shell::util::default_text_options;
+
+use druid::Ui;
+use druid::widget::Widget;
+
+use druid::{BoxConstraints, Geometry, LayoutResult};
+use druid::{HandlerCtx, Id, LayoutCtx, MouseEvent, PaintCtx};
+
+use theme::{solid_brush, Theme};
+
+/// The commands the StatusBar widget accepts through `poke`.
+pub enum StatusBarCommands {
+    View(ViewStatus),
+    Language(Option<String>),
+    /// Replaces all plugin-provided items.
+    Items(Vec<StatusItem>),
+    Theme(Theme),
+    /// Shows a message until it's clicked or replaced.
+    Message(Option<String>),
+}
+
+/// Status of the focused view, as reported by the edit view.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ViewStatus {
+    /// 1-based line and column (in characters) of the primary caret.
+    pub position: Option<(usize, usize)>,
+    pub selections: usize,
+    pub line_ending: &'static str,
+    pub pristine: bool,
+}
+
+impl Default for ViewStatus {
+    fn default() -> ViewStatus {
+        ViewStatus {
+            position: None,
+            selections: 0,
+            line_ending: "LF",
+            pristine: true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Alignment {
+    Left,
+    Right,
+}
+
+/// An item contributed by a plugin with `add_status_item`.
+#[derive(Clone, Debug)]
+pub struct StatusItem {
+    pub source: String,
+    pub key: String,
+    pub value: String,
+    pub alignment: Alignment,
+}
+
+impl StatusItem {
+    pub fn from_json(v: &Value) -> Option<StatusItem> {
+        let alignment = match v["alignment"].as_str() {
+            Some("right") => Alignment::Right,
+            _ => Alignment::Left,
+        };
+        Some(StatusItem {
+            source: v["source"].as_str().unwrap_or_default().to_owned(),
+            key: v["key"].as_str()?.to_owned(),
+            value: v["value"].as_str().unwrap_or_default().to_owned(),
+            alignment,
+        })
+    }
+}
+
+/// Space between items, in px.
+const ITEM_SPACE: f32 = 24.0;
+const PAD: f32 = 6.0;
+const HEIGHT: f32 = 22.0;
+
+pub struct StatusBar {
+    view: ViewStatus,
+    language: Option<String>,
+    items: Vec<StatusItem>,
+    theme: Theme,
+    message: Option<String>,
+    dwrite_factory: directwrite::Factory,
+}
+
+impl Widget for StatusBar {
+    fn paint(&mut self, paint_ctx: &mut PaintCtx, geom: &Geometry) {
+        let text_format = TextFormat::create(&self.dwrite_factory)
+            .with_family("Segoe UI")
+            .with_size(12.0)
+            .build()
+            .unwrap();
+        let rt = paint_ctx.render_target();
+        let bg = if self.message.is_some() {
+            solid_brush(rt, self.theme.find_highlight)
+        } else {
+            solid_brush(rt, self.theme.line_highlight)
+        };
+        let fg = solid_brush(rt, self.theme.foreground);
+        let (x, y) = geom.pos;
+        let (width, height) = geom.size;
+        rt.fill_rectangle((x, y, x + width, y + height), &bg);
+
+        let text_y = y + (height - 15.0) / 2.0;
+        let mut left = x + PAD;
+        let message = self.message.iter().cloned();
+        for text in message.chain(self.left_texts()) {
+            let layout = self.text_layout(&text, &text_format);
+            rt.draw_text_layout((left, text_y), &layout, &fg, default_text_options());
+            left += text_width(&layout, &text) + ITEM_SPACE;
+        }
+        let mut right = x + width - PAD;
+        for text in self.right_texts() {
+            let layout = self.text_layout(&text, &text_format);
+            right -= text_width(&layout, &text);
+            rt.draw_text_layout((right, text_y), &layout, &fg, default_text_options());
+            right -= ITEM_SPACE;
+        }
+    }
+
+    fn layout(&mut self, bc: &BoxConstraints, _children: &[Id], _size: Option<(f32, f32)>,
+        _ctx: &mut LayoutCtx) -> LayoutResult
+    {
+        LayoutResult::Size(bc.constrain((bc.max_width, HEIGHT)))
+    }
+
+    fn poke(&mut self, payload: &mut Any, ctx: &mut HandlerCtx) -> bool {
+        if let Some(cmd) = payload.downcast_ref::<StatusBarCommands>() {
+            match cmd {
+                StatusBarCommands::View(status) => self.view = status.clone(),
+                StatusBarCommands::Language(language) => self.language = language.clone(),
+                StatusBarCommands::Items(items) => self.items = items.clone(),
+                StatusBarCommands::Theme(theme) => self.theme = theme.clone(),
+                StatusBarCommands::Message(message) => self.message = message.clone(),
+            }
+            ctx.invalidate();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn mouse(&mut self, event: &MouseEvent, ctx: &mut HandlerCtx) -> bool {
+        if event.count > 0 && self.message.is_some() {
+            self.message = None;
+            ctx.invalidate();
+        }
+        true
+    }
+}
+
+impl StatusBar {
+    pub fn new() -> StatusBar {
+        StatusBar {
+            view: Default::default(),
+            language: None,
+            items: Vec::new(),
+            theme: Default::default(),
+            message: None,
+            dwrite_factory: directwrite::Factory::new().unwrap(),
+        }
+    }
+
+    pub fn ui(self, ctx: &mut Ui) -> Id {
+        ctx.add(self, &[])
+    }
+
+    /// Texts of the left-aligned items, from left to right.
+    fn left_texts(&self) -> Vec<String> {
+        let mut texts = Vec::new();
+        if let Some((line, col)) = self.view.position {
+            texts.push(format!("Ln {}, Col {}", line, col));
+        }
+        if self.view.selections > 1 {
+            texts.push(format!("{} selections", self.view.selections));
+        }
+        texts.extend(self.items.iter()
+            .filter(|item| item.alignment == Alignment::Left)
+            .map(|item| item.value.clone()));
+        texts
+    }
+
+    /// Texts of the right-aligned items, from right to left.
+    fn right_texts(&self) -> Vec<String> {
+        let mut texts = Vec::new();
+        if !self.view.pristine {
+            texts.push("Modified".to_owned());
+        }
+        texts.push(self.view.line_ending.to_owned());
+        texts.push("UTF-8".to_owned());
+        texts.push(self.language.clone().unwrap_or_else(|| "Plain Text".to_owned()));
+        texts.extend(self.items.iter()
+            .filter(|item| item.alignment == Alignment::Right)
+            .map(|item| item.value.clone()));
+        texts
+    }
+
+    fn text_layout(&self, text: &str, format: &TextFormat) -> TextLayout {
+        TextLayout::create(&self.dwrite_factory)
+            .with_text(text)
+            .with_font(format)
+            .with_width(1e6)
+            .with_height(1e6)
+            .build().unwrap()
+    }
+}
+
+/// Width of the laid out text, in px.
+fn text_width(layout: &TextLayout, text: &str) -> f32 {
+    let len = text.encode_utf16().count() as u32;
+    layout.hit_test_text_position(len, true).map(|pos| pos.point_x).unwrap_or(0.0)
+}
@@ -0,0 +1,153 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The row of tabs above the edit view, one per open document.
+
+use std::any::Any;
+
+use direct2d::RenderTarget;
+use directwrite;
+use directwrite::{TextFormat, TextLayout};
+
+use druid_win_shell::util::default_text_options;
+use druid_win_shell::window::MouseButton;
+
+use druid::Ui;
+use druid::widget::Widget;
+
+use druid::{BoxConstraints, Geometry, LayoutResult};
+use druid::{HandlerCtx, Id, LayoutCtx, MouseEvent, PaintCtx};
+
+use theme::{solid_brush, Theme};
+
+/// The commands the TabBar widget accepts through `poke`.
+pub enum TabBarCommands {
+    /// The tab titles, and the index of the selected tab.
+    Tabs(Vec<String>, usize),
+    Theme(Theme),
+}
+
+/// Sent to listeners when a tab is clicked.
+#[derive(Clone, Copy, Debug)]
+pub enum TabEvent {
+    /// Left click.
+    Select(usize),
+    /// Middle click.
+    Close(usize),
+}
+
+const PAD: f32 = 10.0;
+const HEIGHT: f32 = 24.0;
+const MAX_TAB_WIDTH: f32 = 200.0;
+
+pub struct TabBar {
+    titles: Vec<String>,
+    selected: usize,
+    theme: Theme,
+    /// Right edge of each tab from the last paint, relative to the bar.
+    tab_ends: Vec<f32>,
+    dwrite_factory: directwrite::Factory,
+}
+
+impl Widget for TabBar {
+    fn paint(&mut self, paint_ctx: &mut PaintCtx, geom: &Geometry) {
+        let text_format = TextFormat::create(&self.dwrite_factory)
+            .with_family("Segoe UI")
+            .with_size(12.0)
+            .build()
+            .unwrap();
+        let rt = paint_ctx.render_target();
+        let bg = solid_brush(rt, self.theme.line_highlight);
+        let selected_bg = solid_brush(rt, self.theme.background);
+        let fg = solid_brush(rt, self.theme.foreground);
+        let (x, y) = geom.pos;
+        let (width, height) = geom.size;
+        rt.fill_rectangle((x, y, x + width, y + height), &bg);
+
+        self.tab_ends.clear();
+        let text_y = y + (height - 15.0) / 2.0;
+        let mut left = x;
+        for (i, title) in self.titles.iter().enumerate() {
+            let layout = TextLayout::create(&self.dwrite_factory)
+                .with_text(title)
+                .with_font(&text_format)
+                .with_width(MAX_TAB_WIDTH - 2.0 * PAD)
+                .with_height(height)
+                .build().unwrap();
+            let len = title.encode_utf16().count() as u32;
+            let text_width = layout.hit_test_text_position(len, true)
+                .map(|pos| pos.point_x)
+                .unwrap_or(0.0);
+            let right = left + (text_width + 2.0 * PAD).min(MAX_TAB_WIDTH);
+            if i == self.selected {
+                rt.fill_rectangle((left, y, right, y + height), &selected_bg);
+            }
+            rt.draw_text_layout((left + PAD, text_y), &layout, &fg, default_text_options());
+            left = right;
+            self.tab_ends.push(right - x);
+        }
+    }
+
+    fn layout(&mut self, bc: &BoxConstraints, _children: &[Id], _size: Option<(f32, f32)>,
+        _ctx: &mut LayoutCtx) -> LayoutResult
+    {
+        LayoutResult::Size(bc.constrain((bc.max_width, HEIGHT)))
+    }
+
+    fn poke(&mut self, payload: &mut Any, ctx: &mut HandlerCtx) -> bool {
+        if let Some(cmd) = payload.downcast_ref::<TabBarCommands>() {
+            match cmd {
+                TabBarCommands::Tabs(titles, selected) => {
+                    self.titles = titles.clone();
+                    self.selected = *selected;
+                }
+                TabBarCommands::Theme(theme) => self.theme = theme.clone(),
+            }
+            ctx.invalidate();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn mouse(&mut self, event: &MouseEvent, ctx: &mut HandlerCtx) -> bool {
+        if event.count == 0 {
+            return true;
+        }
+        if let Some(index) = self.tab_ends.iter().position(|&end| event.x < end) {
+            match event.which {
+                MouseButton::Left => ctx.send_event(TabEvent::Select(index)),
+                MouseButton::Middle => ctx.send_event(TabEvent::Close(index)),
+                _ => (),
+            }
+        }
+        true
+    }
+}
+
+impl TabBar {
+    pub fn new() -> TabBar {
+        TabBar {
+            titles: Vec::new(),
+            selected: 0,
+            theme: Default::default(),
+            tab_ends: Vec::new(),
+            dwrite_factory: directwrite::Factory::new().unwrap(),
+        }
+    }
+
+    pub fn ui(self, ctx: &mut Ui) -> Id {
+        ctx.add(self, &[])
+    }
+}
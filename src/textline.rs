@@ -16,12 +16,19 @@
 
 use direct2d::RenderTarget;
 use direct2d::brush::SolidColorBrush;
+use direct2d::enums::AntialiasMode;
+use direct2d::math::RectF;
 use directwrite;
 use directwrite::{TextFormat, TextLayout};
 
 use druid_win_shell::util::default_text_options;
 
-use linecache::{Line, StyleSpan};
+use caret::{CaretShape, CaretStyle};
+use linecache::{AnnotationType, Line};
+
+use std::ops::Range;
+
+use serde_json::Value;
 
 pub struct TextLine {
     layout: TextLayout,
@@ -29,14 +36,20 @@ pub struct TextLine {
     /// don't have to re-measure in draw_cursor, but whatever.
     cursor: Vec<usize>,
 
-    /// Style spans (internally in utf-16 code units). Arguably could be resolved
-    /// to floats.
-    styles: Vec<StyleSpan>,
+    /// Annotated ranges (in utf-16 code units), in drawing order. Arguably
+    /// could be resolved to floats.
+    annotations: Vec<(AnnotationType, Range<usize>)>,
+
+    /// Height of the line in px, which annotations and carets fill.
+    height: f32,
 }
 
 impl TextLine {
-    pub fn create_from_line(line: &Line, factory: &directwrite::Factory, format: &TextFormat)
-        -> TextLine
+    /// Creates a text line `height` px tall. `annotations` are the ranges
+    /// from the update's annotations; the reserved style ids 0 (selection)
+    /// and 1 (find) used by older cores are added to them.
+    pub fn create_from_line(line: &Line, mut annotations: Vec<(AnnotationType, Range<usize>)>,
+        factory: &directwrite::Factory, format: &TextFormat, height: f32) -> TextLine
     {
         let text = line.text();
         let trimmed_text = text.trim_right_matches(|c| c == '\r' || c == '\n');
@@ -46,20 +59,40 @@ impl TextLine {
             .with_width(1e6)
             .with_height(1e6)
             .build().unwrap();
+        for style in line.styles() {
+            let ty = match style.style_id {
+                0 => AnnotationType::Selection,
+                1 => AnnotationType::Find,
+                _ => continue,
+            };
+            annotations.push((ty, style.range.clone()));
+        }
+        // Plugin annotations go under find results, which go under selections.
+        annotations.sort_by_key(|&(ref ty, _)| match *ty {
+            AnnotationType::Other(_) => 0,
+            AnnotationType::Find => 1,
+            AnnotationType::Selection => 2,
+        });
         TextLine {
             layout,
             cursor: line.cursor().to_owned(),
-            styles: line.styles().to_vec(),
+            annotations,
+            height,
         }
     }
 
-    pub fn draw_bg<R: RenderTarget>(&self, rt: &mut R, x: f32, y: f32, bg: &SolidColorBrush) {
-        for style in &self.styles {
+    /// Draw the annotation backgrounds, using `brush` to pick the color for
+    /// each annotation type.
+    pub fn draw_bg<'b, R, F>(&self, rt: &mut R, x: f32, y: f32, brush: F)
+        where R: RenderTarget, F: Fn(&AnnotationType) -> &'b SolidColorBrush
+    {
+        for &(ref ty, ref range) in &self.annotations {
             if let (Some(start), Some(end)) =
-                (self.layout.hit_test_text_position(style.range.start as u32, true),
-                 self.layout.hit_test_text_position(style.range.end as u32, true))
+                (self.layout.hit_test_text_position(range.start as u32, true),
+                 self.layout.hit_test_text_position(range.end as u32, true))
             {
-                rt.fill_rectangle((x + start.point_x, y, x + end.point_x, y + 17.0), bg);
+                let rect = (x + start.point_x, y, x + end.point_x, y + self.height);
+                rt.fill_rectangle(rect, brush(ty));
             }
         }
     }
@@ -72,17 +105,49 @@ impl TextLine {
         rt.draw_text_layout((x, y), &self.layout, fg, default_text_options());
     }
 
-    /// Draw the carets.
-    pub fn draw_cursor<R:RenderTarget>(&self, rt: &mut R, x: f32, y: f32, fg: &SolidColorBrush) {
-        for &offset in &self.cursor {
-            if let Some(pos) = self.layout.hit_test_text_position(offset as u32, true) {
-                let xc = x + pos.point_x;
-                rt.draw_line((xc, y), (xc, y + 17.0),
-                    fg, 1.0, None);
+    /// The rectangles covered by the carets, as (left, top, right, bottom).
+    pub fn cursor_rects(&self, x: f32, y: f32, style: &CaretStyle) -> Vec<(f32, f32, f32, f32)> {
+        self.cursor.iter().filter_map(|&offset| {
+            let pos = self.layout.hit_test_text_position(offset as u32, true)?;
+            let x0 = x + pos.point_x;
+            let x1 = match self.layout.hit_test_text_position(offset as u32 + 1, true) {
+                Some(next) => x + next.point_x,
+                None => x0,
+            };
+            Some(style.rect(x0, x1, y, self.height))
+        }).collect()
+    }
+
+    /// Draw the carets, over the text.
+    ///
+    /// `primary` is the index into this line's carets of the primary caret,
+    /// which is drawn with `fg`; all other carets are drawn with `secondary`.
+    /// Hollow carets are drawn as outlines. The character under a solid
+    /// block caret is drawn again in `bg`, so it stays readable.
+    pub fn draw_cursor<R: RenderTarget>(&self, rt: &mut R, x: f32, y: f32, style: &CaretStyle,
+        primary: Option<usize>, hollow: bool, fg: &SolidColorBrush, secondary: &SolidColorBrush,
+        bg: &SolidColorBrush)
+    {
+        for (i, rect) in self.cursor_rects(x, y, style).into_iter().enumerate() {
+            let brush = if primary == Some(i) { fg } else { secondary };
+            if hollow {
+                rt.draw_rectangle(rect, brush, 1.0, None);
+            } else {
+                rt.fill_rectangle(rect, brush);
+                if style.shape == CaretShape::Block {
+                    rt.push_axis_aligned_clip(RectF::from(rect), AntialiasMode::Aliased);
+                    self.draw_text(rt, x, y, bg);
+                    rt.pop_axis_aligned_clip();
+                }
             }
         }
     }
 
+    /// Whether there are any carets on this line.
+    pub fn has_cursor(&self) -> bool {
+        !self.cursor.is_empty()
+    }
+
     /// Return the utf-8 offset corresponding to the point (relative to top left corner).
     ///
     /// The `text` parameter is for utf-16 to utf-8 conversion, and is to avoid having
@@ -108,3 +173,44 @@ fn conv_utf16_to_utf8_offset(s: &str, utf16_offset: usize) -> usize {
     }
     s.len()
 }
+
+/// The height in px of a line of text in `format`.
+pub fn line_height(factory: &directwrite::Factory, format: &TextFormat) -> Option<f32> {
+    let layout = TextLayout::create(factory)
+        .with_text("X")
+        .with_font(format)
+        .with_width(1e6)
+        .with_height(1e6)
+        .build().ok()?;
+    layout.hit_test_text_position(0, false).map(|pos| pos.metrics.height())
+}
+
+/// Answers the core's `measure_width` request: the width in px of each of
+/// the strings, laid out in `format`.
+///
+/// The params are a list of `{"id": style id, "strings": [...]}`; all styles
+/// are measured in the base format for now.
+pub fn measure_width(factory: &directwrite::Factory, format: &TextFormat, params: &Value)
+    -> Option<Value>
+{
+    let mut result = Vec::new();
+    for request in params.as_array()? {
+        let mut widths = Vec::new();
+        for text in request["strings"].as_array()? {
+            let text = text.as_str()?;
+            let layout = TextLayout::create(factory)
+                .with_text(text)
+                .with_font(format)
+                .with_width(1e6)
+                .with_height(1e6)
+                .build().ok()?;
+            let len = text.encode_utf16().count() as u32;
+            let width = layout.hit_test_text_position(len, true)
+                .map(|pos| pos.point_x)
+                .unwrap_or(0.0);
+            widths.push(json!(width));
+        }
+        result.push(Value::Array(widths));
+    }
+    Some(Value::Array(result))
+}
@@ -0,0 +1,117 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Theme colors, as sent by the core in `theme_changed`.
+
+use serde_json::Value;
+
+use direct2d::brush::SolidColorBrush;
+use direct2d::math::ColorF;
+use direct2d::RenderTarget;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Color {
+    /// 0xRRGGBB
+    pub rgb: u32,
+    pub alpha: f32,
+}
+
+impl Color {
+    pub fn rgb(rgb: u32) -> Color {
+        Color { rgb, alpha: 1.0 }
+    }
+
+    /// Parses a `{"r": .., "g": .., "b": .., "a": ..}` object.
+    fn from_json(v: &Value) -> Option<Color> {
+        let r = v["r"].as_u64()? as u32;
+        let g = v["g"].as_u64()? as u32;
+        let b = v["b"].as_u64()? as u32;
+        let a = v["a"].as_u64().unwrap_or(255) as f32;
+        Some(Color {
+            rgb: (r << 16) | (g << 8) | b,
+            alpha: a / 255.0,
+        })
+    }
+
+    pub fn with_alpha(self, alpha: f32) -> Color {
+        Color { rgb: self.rgb, alpha }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Theme {
+    pub foreground: Color,
+    pub background: Color,
+    pub caret: Color,
+    /// Caret color used for all but the primary caret when there are several.
+    pub secondary_caret: Color,
+    /// Background of lines containing a caret.
+    pub line_highlight: Color,
+    pub selection: Color,
+    pub find_highlight: Color,
+    /// Background of plugin-provided annotations.
+    pub annotation: Color,
+}
+
+impl Default for Theme {
+    fn default() -> Theme {
+        let foreground = Color::rgb(0xf0f0ea);
+        Theme {
+            foreground,
+            background: Color::rgb(0x272822),
+            caret: foreground,
+            secondary_caret: foreground.with_alpha(0.5),
+            line_highlight: Color::rgb(0x3e3d32),
+            selection: Color::rgb(0x49483e),
+            find_highlight: Color::rgb(0x75715e),
+            annotation: Color::rgb(0x3e4a5c),
+        }
+    }
+}
+
+impl Theme {
+    /// Builds a theme from the `theme` field of a `theme_changed` notification.
+    ///
+    /// Colors missing from the message keep their default value.
+    pub fn from_json(v: &Value) -> Theme {
+        let mut theme = Theme::default();
+        if let Some(c) = Color::from_json(&v["foreground"]) {
+            theme.foreground = c;
+        }
+        if let Some(c) = Color::from_json(&v["background"]) {
+            theme.background = c;
+        }
+        theme.caret = Color::from_json(&v["caret"]).unwrap_or(theme.foreground);
+        theme.secondary_caret = theme.caret.with_alpha(theme.caret.alpha * 0.5);
+        if let Some(c) = Color::from_json(&v["line_highlight"]) {
+            theme.line_highlight = c;
+        }
+        if let Some(c) = Color::from_json(&v["selection"]) {
+            theme.selection = c;
+        }
+        if let Some(c) = Color::from_json(&v["find_highlight"]) {
+            theme.find_highlight = c;
+        }
+        if let Some(c) = Color::from_json(&v["highlight"]) {
+            theme.annotation = c;
+        }
+        theme
+    }
+}
+
+pub fn solid_brush<R: RenderTarget>(rt: &mut R, color: Color) -> SolidColorBrush {
+    SolidColorBrush::create(rt)
+        .with_color(ColorF::uint_rgb(color.rgb, color.alpha))
+        .build().unwrap()
+}
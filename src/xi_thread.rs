@@ -14,9 +14,11 @@
 
 //! Startup and communication with the xi core thread.
 
-use std::io::{self, BufRead, ErrorKind, Read, Write};
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
 use std::sync::mpsc::{channel, Receiver, Sender};
-use std::thread;
+use std::thread::{self, JoinHandle};
 #[allow(unused_imports)]
 use std::time::Duration;
 
@@ -31,9 +33,17 @@ use xi_rpc::RpcLoop;
 
 pub struct XiPeer {
     tx: Sender<String>,
+    /// Threads that end when the core does.
+    threads: Vec<JoinHandle<()>>,
 }
 
 impl XiPeer {
+    /// Closes the core's input, so that it exits once it has handled what
+    /// was sent so far. Returns the threads to join once it has.
+    pub fn close(self) -> Vec<JoinHandle<()>> {
+        self.threads
+    }
+
     pub fn send(&self, s: String) {
         let _ = self.tx.send(s);
     }
@@ -41,6 +51,14 @@ impl XiPeer {
     pub fn send_json(&self, v: &Value) {
         self.send(serde_json::to_string(v).unwrap());
     }
+
+    /// A peer that isn't connected to a core. What's sent to it comes out
+    /// of the returned receiver, for tests to answer.
+    #[cfg(test)]
+    pub fn in_memory() -> (XiPeer, Receiver<String>) {
+        let (tx, rx) = channel();
+        (XiPeer { tx, threads: Vec::new() }, rx)
+    }
 }
 
 pub fn start_xi_thread() -> (XiPeer, Receiver<Value>) {
@@ -52,15 +70,55 @@ pub fn start_xi_thread() -> (XiPeer, Receiver<Value>) {
     };
     let mut state = XiCore::new();
     let mut rpc_looper = RpcLoop::new(from_core_tx);
-    thread::spawn(move ||
+    let thread = thread::spawn(move ||
         rpc_looper.mainloop(|| to_core_rx, &mut state)
     );
     let peer = XiPeer {
         tx: to_core_tx,
+        threads: vec![thread],
     };
     (peer, from_core_rx)
 }
 
+/// Starts an external core executable, talking to it over stdin and stdout.
+pub fn start_xi_process(path: &Path) -> io::Result<(XiPeer, Receiver<Value>)> {
+    let mut child = Command::new(path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().unwrap();
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    let (to_core_tx, to_core_rx) = channel::<String>();
+    let (from_core_tx, from_core_rx) = channel();
+    let writer = thread::spawn(move || {
+        for s in to_core_rx {
+            if writeln!(stdin, "{}", s).and_then(|_| stdin.flush()).is_err() {
+                break;
+            }
+        }
+    });
+    let reader = thread::spawn(move || {
+        for line in stdout.lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            match serde_json::from_str::<Value>(&line) {
+                Ok(json) => if from_core_tx.send(json).is_err() {
+                    break;
+                },
+                Err(e) => error!("bad json from core: {}", e),
+            }
+        }
+        let _ = child.wait();
+    });
+    let peer = XiPeer {
+        tx: to_core_tx,
+        threads: vec![writer, reader],
+    };
+    Ok((peer, from_core_rx))
+}
+
 struct ChanReader(Receiver<String>);
 
 impl Read for ChanReader {